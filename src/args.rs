@@ -7,11 +7,16 @@ use clap::builder::styling;
 use parse_size::Config;
 use simplelog::*;
 
+use crate::cbt::BlockHash;
+use crate::cdc;
+use crate::compression::Algorithm;
+use crate::hash::HashAlgorithm;
+
 const DEFAULT_BLOCK_SIZE: usize = 32768;
 
 /// Device imaging tool.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, color = clap::ColorChoice::Always)]
+#[command(version, about, long_about = None, color = clap::ColorChoice::Always, styles = STYLES)]
 pub struct Args {
     /// device to image
     #[arg(short, long, required = true, value_name = "DEVICE")]
@@ -29,6 +34,10 @@ pub struct Args {
     #[arg(long, short)]
     nb_threads: Option<usize>,
 
+    /// number of threads compressing and classifying blocks
+    #[arg(long, value_name = "NB_WORKERS")]
+    nb_workers: Option<usize>,
+
     /// stops after reading count blocks
     #[arg(long, short, value_name = "NB_BLOCKS")]
     pub count: Option<u64>,
@@ -37,14 +46,102 @@ pub struct Args {
     #[arg(long)]
     pub log: Option<PathBuf>,
 
-    /// LZ4 compression
-    #[arg(long)]
-    pub compress: bool,
+    /// compress blocks with LZ4 (default) or zstd. In dd mode, output is a standard lz4, zstd or
+    /// gzip stream
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "lz4", value_name = "ALGO")]
+    pub compress: Option<Algorithm>,
+
+    /// compression level: 1 to 22 for zstd, 1 to 12 for LZ4 high compression mode, 1 to 9 for gzip
+    #[arg(long, requires = "compress", value_name = "LEVEL")]
+    pub level: Option<i32>,
+
+    /// compress blocks with a zstd dictionary trained on the beginning of the source
+    #[arg(long, requires = "compress", conflicts_with = "dd")]
+    pub dictionary: bool,
+
+    /// amount of data sampled at the beginning of the source to train the dictionary
+    #[arg(
+        long,
+        requires = "dictionary",
+        default_value = "16M",
+        value_name = "SIZE"
+    )]
+    dict_sample: String,
+
+    /// replace blocks already written by a reference to them
+    #[arg(long, conflicts_with = "dd")]
+    pub dedup: bool,
+
+    /// compare duplicate blocks byte per byte to guard against hash collisions
+    #[arg(long, requires = "dedup")]
+    pub dedup_verify: bool,
+
+    /// memory used to remember blocks already written, about 40 bytes per distinct block.
+    /// Once reached, new blocks are written as is
+    #[arg(long, default_value = "1G", value_name = "SIZE")]
+    dedup_memory: String,
+
+    /// cut the source into content-defined chunks averaging the block size, so shifted data
+    /// is still deduplicated (implies --dedup)
+    #[arg(long, conflicts_with = "dd")]
+    pub cdc: bool,
+
+    /// keep data chunks once in this repository directory, --of being a manifest listing them.
+    /// When reading a manifest, repository to use instead of the one recorded in it
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["dd", "dictionary", "dedup"])]
+    pub repo: Option<PathBuf>,
+
+    /// only store blocks which changed since this previous image of the same source, others
+    /// being taken from it. Its blocks are compared using the hashes stored by --block-hashes.
+    /// When reading an incremental image, parent to use instead of the one recorded in it
+    #[arg(long, value_name = "IMAGE", conflicts_with_all = ["dd", "cdc", "repo", "changed_since"])]
+    pub parent: Option<PathBuf>,
+
+    /// blake3 --cbt sidecar written along with the --parent image, when the image doesn't
+    /// store its block hashes
+    #[arg(long, value_name = "FILE", requires = "parent")]
+    pub parent_hashes: Option<PathBuf>,
+
+    /// store the blake3 digest of each block in the image, so it can be the --parent of a later
+    /// acquisition. Always done for incremental images
+    #[arg(long, conflicts_with_all = ["dd", "cdc"])]
+    pub block_hashes: bool,
+
+    /// write a sidecar file holding a digest per block, for a later --changed-since
+    #[arg(long, value_name = "FILE", conflicts_with = "cdc")]
+    pub cbt: Option<PathBuf>,
+
+    /// algorithm of block digests in the --cbt sidecar [default: xxh3, or blake3 when block
+    /// hashes are stored in the image]
+    #[arg(
+        long,
+        value_enum,
+        requires = "cbt",
+        conflicts_with = "changed_since",
+        value_name = "ALGO"
+    )]
+    pub cbt_hash: Option<BlockHash>,
+
+    /// report block ranges changed since the acquisition which wrote this sidecar
+    #[arg(long, value_name = "FILE", conflicts_with = "cdc")]
+    pub changed_since: Option<PathBuf>,
+
+    /// in dd mode, only write changed blocks, updating --of in place
+    #[arg(long, requires_all = ["changed_since", "dd", "of"], conflicts_with_all = ["compress", "sparse", "segment_size"])]
+    pub changed_only: bool,
+
+    /// store compressed blocks only if compression saves at least this percentage of their size
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u8).range(0..100), value_name = "PERCENT")]
+    pub min_saving: u8,
 
     /// if set, output is similar to dd
     #[arg(long)]
     pub dd: bool,
 
+    /// in dd mode, leave zero blocks as holes in output file instead of writing them
+    #[arg(long, requires = "dd", conflicts_with = "compress")]
+    pub sparse: bool,
+
     /// Verbose mode (-v, -vv, -vvv)
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -57,9 +154,87 @@ pub struct Args {
     #[arg(long)]
     pub blake3: bool,
 
+    /// calculate digests of the input file or device with these algorithms, all in the same
+    /// pass (can be repeated or comma separated)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "ALGO")]
+    pub hash: Vec<HashAlgorithm>,
+
+    /// also calculate digests of every window of this size, stored in the image. In dd mode,
+    /// they're only written to --hash-log
+    #[arg(long, value_name = "SIZE")]
+    hash_window: Option<String>,
+
+    /// write window digests to this text file, one START-END ALGO:DIGEST line each
+    #[arg(long, requires = "hash_window", value_name = "FILE")]
+    pub hash_log: Option<PathBuf>,
+
+    /// build a Merkle tree over blocks, stored in the image, whose root is its fingerprint
+    #[arg(long)]
+    pub merkle: bool,
+
+    /// only check this block against the Merkle tree of the image when verifying
+    #[arg(long, requires = "verify", value_name = "BLOCK")]
+    pub block: Option<u64>,
+
+    /// write each digest to OUTPUT.ALGO, in the format of sha256sum and friends
+    #[arg(long, requires = "of")]
+    pub hash_files: bool,
+
     /// the number of 4096-aligned buffers used in the registry to communicate to the kernel
     #[arg(long, default_value = "8", value_name = "NB_BUFFERS")]
     pub buffers: usize,
+
+    /// restore the dimg image given by --if onto the --of file or device
+    #[arg(long, requires = "of", conflicts_with_all = ["compress", "dd"])]
+    pub restore: bool,
+
+    /// let --restore overwrite an existing regular file
+    #[arg(long, requires = "restore")]
+    pub force: bool,
+
+    /// re-hash the content of the dimg image given by --if and compare with expected digests
+    #[arg(long, conflicts_with_all = ["compress", "dd", "restore", "of"])]
+    pub verify: bool,
+
+    /// expected digest of the source when verifying, e.g: sha256:<HEX> (can be repeated)
+    #[arg(long, requires = "verify", value_name = "ALGO:DIGEST")]
+    pub expect: Vec<String>,
+
+    /// split output into files of this maximum size: OUTPUT.001, OUTPUT.002, ...
+    #[arg(long, requires = "of", value_name = "SEGMENT_SIZE")]
+    segment_size: Option<String>,
+
+    /// case number stored in image metadata
+    #[arg(long, value_name = "CASE_NUMBER")]
+    pub case_number: Option<String>,
+
+    /// examiner name stored in image metadata
+    #[arg(long, value_name = "EXAMINER")]
+    pub examiner: Option<String>,
+
+    /// evidence ID stored in image metadata
+    #[arg(long, value_name = "EVIDENCE_ID")]
+    pub evidence_id: Option<String>,
+
+    /// description of the evidence stored in image metadata
+    #[arg(long, value_name = "DESCRIPTION")]
+    pub description: Option<String>,
+
+    /// free-form notes stored in image metadata
+    #[arg(long, value_name = "NOTES")]
+    pub notes: Option<String>,
+
+    /// file of key = value lines stored in image metadata, overridden by metadata options
+    #[arg(long, value_name = "METADATA_FILE")]
+    pub metadata: Option<PathBuf>,
+
+    /// print a summary of the dimg image given by --if
+    #[arg(long, conflicts_with_all = ["compress", "dd", "restore", "verify", "of"])]
+    pub info: bool,
+
+    /// print info as JSON
+    #[arg(long, requires = "info")]
+    pub json: bool,
 }
 
 impl Args {
@@ -74,6 +249,74 @@ impl Args {
         }
     }
 
+    // algorithms given by --hash, --sha256 and --blake3, each once
+    pub fn hash_algorithms(&self) -> Vec<HashAlgorithm> {
+        let mut algorithms = self.hash.clone();
+        if self.sha256 {
+            algorithms.push(HashAlgorithm::Sha256);
+        }
+        if self.blake3 {
+            algorithms.push(HashAlgorithm::Blake3);
+        }
+
+        algorithms.sort();
+        algorithms.dedup();
+        algorithms
+    }
+
+    pub fn segment_size(&self) -> Option<u64> {
+        let cfg = Config::new().with_binary();
+
+        // convert any human units
+        self.segment_size
+            .as_ref()
+            .and_then(|size| cfg.parse_size(size).ok())
+    }
+
+    pub fn hash_window(&self) -> Option<u64> {
+        let cfg = Config::new().with_binary();
+
+        // convert any human units
+        self.hash_window
+            .as_ref()
+            .and_then(|size| cfg.parse_size(size).ok())
+    }
+
+    // true if block digests are stored in the image
+    pub fn store_block_hashes(&self) -> bool {
+        self.block_hashes || self.parent.is_some()
+    }
+
+    // algorithm of block digests, if any are needed. Images only store blake3 ones
+    pub fn block_hash(&self) -> Option<BlockHash> {
+        if self.store_block_hashes() {
+            Some(BlockHash::Blake3)
+        } else if self.cbt.is_some() || self.changed_since.is_some() {
+            Some(self.cbt_hash.unwrap_or_default())
+        } else {
+            None
+        }
+    }
+
+    pub fn dedup_memory(&self) -> Option<u64> {
+        let cfg = Config::new().with_binary();
+
+        // convert any human units
+        cfg.parse_size(&self.dedup_memory).ok()
+    }
+
+    pub fn dict_sample(&self) -> Option<u64> {
+        let cfg = Config::new().with_binary();
+
+        // convert any human units
+        cfg.parse_size(&self.dict_sample).ok()
+    }
+
+    pub fn nb_workers(&self) -> usize {
+        // defaults to number of threads on CPU
+        self.nb_workers.unwrap_or_else(num_cpus::get)
+    }
+
     pub fn nb_threads(&self) -> usize {
         // defaults to number of threads on CPU
         if let Some(n) = self.nb_threads {
@@ -92,6 +335,8 @@ pub fn get_args() -> anyhow::Result<Args> {
         args.nb_threads = Some(num_cpus::get());
     }
 
+    check_args(&args)?;
+
     // extract loglevel from verbose flag
     let level = match args.verbose {
         0 => log::LevelFilter::Warn,
@@ -102,7 +347,7 @@ pub fn get_args() -> anyhow::Result<Args> {
 
     // manage log file
     if let Some(path) = &args.log {
-        init_write_logger(&path, level)?;
+        init_write_logger(path, level)?;
     } else {
         init_term_logger(level)?;
     }
@@ -110,6 +355,83 @@ pub fn get_args() -> anyhow::Result<Args> {
     Ok(args)
 }
 
+// check options which can't be told incompatible by clap
+fn check_args(args: &Args) -> anyhow::Result<()> {
+    // a segment must at least hold a few chunks
+    if let Some(size) = &args.segment_size {
+        match args.segment_size() {
+            Some(n) if n >= 2 * args.block_size() as u64 => (),
+            Some(_) => anyhow::bail!(
+                "segment size {size} must be at least twice the block size {}",
+                args.block_size()
+            ),
+            None => anyhow::bail!("invalid segment size {size}"),
+        }
+    }
+
+    // sum files need a digest, and in dd mode name the copy, which must be a plain file
+    if args.hash_files && args.hash_algorithms().is_empty() {
+        anyhow::bail!("--hash-files needs digests given by --hash");
+    }
+    if args.hash_files && args.dd && (args.compress.is_some() || args.segment_size.is_some()) {
+        anyhow::bail!("--hash-files in dd mode needs a copy neither compressed nor segmented");
+    }
+
+    // windows are hashed with the algorithms of the whole source
+    if let Some(size) = &args.hash_window {
+        match args.hash_window() {
+            Some(n) if n > 0 => (),
+            _ => anyhow::bail!("invalid hash window size {size}"),
+        }
+        if args.hash_algorithms().is_empty() {
+            anyhow::bail!("--hash-window needs digests given by --hash");
+        }
+
+        // window digests can't be stored in a dd copy
+        if args.dd && args.hash_log.is_none() {
+            anyhow::bail!("--hash-window in dd mode needs --hash-log to keep window digests");
+        }
+    }
+
+    // a sidecar shares the digests stored in the image
+    if args.store_block_hashes() && args.cbt_hash == Some(BlockHash::Xxh3) {
+        anyhow::bail!("block hashes stored in the image are blake3, not xxh3");
+    }
+
+    // images are made of chunks compressed with LZ4 or zstd only
+    if args.compress == Some(Algorithm::Gzip) && !args.dd {
+        anyhow::bail!("gzip compression is only available with --dd");
+    }
+
+    // dictionaries are a zstd feature
+    if args.dictionary && args.compress != Some(Algorithm::Zstd) {
+        anyhow::bail!("--dictionary is only available with zstd compression");
+    }
+    if args.dedup_memory().is_none() {
+        anyhow::bail!("invalid deduplication memory size {}", args.dedup_memory);
+    }
+    if args.dict_sample().is_none() {
+        anyhow::bail!("invalid dictionary sample size {}", args.dict_sample);
+    }
+
+    // acquisitions into a repository write their manifest to --of
+    if args.repo.is_some() && args.of.is_none() && !args.info && !args.verify {
+        anyhow::bail!("--repo needs --of to write the manifest of the acquisition");
+    }
+
+    // chunk sizes are derived from block size
+    if args.cdc {
+        cdc::check_block_size(args.block_size())?;
+    }
+
+    // level depends on algorithm
+    if let (Some(algorithm), Some(level)) = (args.compress, args.level) {
+        algorithm.check_level(level)?;
+    }
+
+    Ok(())
+}
+
 // colors when displaying
 const STYLES: styling::Styles = styling::Styles::styled()
     .header(styling::AnsiColor::Green.on_default().bold())
@@ -153,3 +475,65 @@ fn init_term_logger(level: log::LevelFilter) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(args: &[&str]) -> anyhow::Result<()> {
+        let args = Args::try_parse_from(["dimg", "--if", "/dev/null"].iter().chain(args))?;
+        check_args(&args)
+    }
+
+    #[test]
+    fn hash_files() {
+        assert!(
+            check(&[
+                "--of",
+                "copy.dd",
+                "--dd",
+                "--hash",
+                "sha256",
+                "--hash-files"
+            ])
+            .is_ok()
+        );
+        assert!(check(&["--of", "copy.dd", "--dd", "--hash-files"]).is_err());
+
+        // sum files would name a file which is not the copy
+        let compressed = ["--of", "copy.gz", "--dd", "--compress", "gzip"];
+        let segmented = ["--of", "copy.dd", "--dd", "--segment-size", "1M"];
+        for args in [&compressed, &segmented] {
+            let args = [&args[..], &["--hash", "sha256", "--hash-files"]].concat();
+            assert!(check(&args).is_err());
+        }
+    }
+
+    #[test]
+    fn hash_window() {
+        let dd = [
+            "--of",
+            "copy.dd",
+            "--dd",
+            "--hash",
+            "sha256",
+            "--hash-window",
+            "1M",
+        ];
+        assert!(check(&dd).is_err());
+        assert!(check(&[&dd[..], &["--hash-log", "copy.log"]].concat()).is_ok());
+
+        // images store them
+        assert!(
+            check(&[
+                "--of",
+                "image.img",
+                "--hash",
+                "sha256",
+                "--hash-window",
+                "1M"
+            ])
+            .is_ok()
+        );
+    }
+}
@@ -0,0 +1,217 @@
+// changed-block tracking sidecar: a digest per block of the source, so a later acquisition of
+// the same source can tell which blocks changed, with or without writing an image. Images
+// store the same list as a section, so they can be the parent of an incremental one
+//
+// layout (big-endian):
+//
+// magic (8) | version (2) | algorithm (1) | block size (8) | source size (8) | count (8)
+// | digest * count
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, anyhow, bail};
+use clap::ValueEnum;
+use xxhash_rust::xxh3::xxh3_128;
+
+// identifies a sidecar
+const MAGIC: &[u8; 8] = b"DIMGCBT\0";
+const VERSION: u16 = 1;
+
+// length of what precedes digests
+const HEADER_LEN: usize = 8 + 2 + 1 + 8 + 8 + 8;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ValueEnum)]
+#[repr(u8)]
+pub enum BlockHash {
+    // fast, to find changes on a trusted source
+    #[default]
+    Xxh3 = 1,
+
+    // cryptographic, when changes could be crafted
+    Blake3 = 2,
+}
+
+impl BlockHash {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            BlockHash::Xxh3 => xxh3_128(data).to_be_bytes().to_vec(),
+            BlockHash::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+
+    // length of a digest
+    pub fn len(&self) -> usize {
+        match self {
+            BlockHash::Xxh3 => 16,
+            BlockHash::Blake3 => 32,
+        }
+    }
+}
+
+impl TryFrom<u8> for BlockHash {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(BlockHash::Xxh3),
+            2 => Ok(BlockHash::Blake3),
+            _ => Err(anyhow!("unknown block hash algorithm {value}")),
+        }
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockHash::Xxh3 => write!(f, "xxh3"),
+            BlockHash::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+// digests of all blocks of a source, in block order
+#[derive(Debug, PartialEq)]
+pub struct BlockHashes {
+    pub algorithm: BlockHash,
+    pub block_size: u64,
+    pub source_size: u64,
+
+    // digests one after the other
+    digests: Vec<u8>,
+}
+
+impl BlockHashes {
+    pub fn new(algorithm: BlockHash, block_size: u64, source_size: u64) -> Self {
+        Self {
+            algorithm,
+            block_size,
+            source_size,
+            digests: Vec::new(),
+        }
+    }
+
+    // add digest of the next block
+    pub fn push(&mut self, digest: &[u8]) {
+        self.digests.extend_from_slice(digest);
+    }
+
+    // digest of a block, if the source had it
+    pub fn get(&self, block: u64) -> Option<&[u8]> {
+        let len = self.algorithm.len();
+        let start = block as usize * len;
+        self.digests.get(start..start + len)
+    }
+
+    // number of blocks
+    pub fn len(&self) -> u64 {
+        (self.digests.len() / self.algorithm.len()) as u64
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("unable to create sidecar {}", path.display()))?;
+        let mut dst = BufWriter::new(file);
+
+        self.write_section(&mut dst)?;
+        dst.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("unable to open sidecar {}", path.display()))?;
+
+        Self::read_section(&mut BufReader::new(file))
+            .with_context(|| format!("unable to read sidecar {}", path.display()))
+    }
+
+    // write digests as an image section, returning the number of bytes written
+    pub fn write_section<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        dst.write_all(MAGIC)?;
+        dst.write_all(&VERSION.to_be_bytes())?;
+        dst.write_all(&[self.algorithm as u8])?;
+        dst.write_all(&self.block_size.to_be_bytes())?;
+        dst.write_all(&self.source_size.to_be_bytes())?;
+        dst.write_all(&self.len().to_be_bytes())?;
+        dst.write_all(&self.digests)?;
+
+        Ok(HEADER_LEN + self.digests.len())
+    }
+
+    // read digests up to the end of src, which is limited to the section by callers
+    pub fn read_section<R: Read>(src: &mut R) -> anyhow::Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        src.read_exact(&mut header)
+            .map_err(|e| anyhow!("unable to read block hashes header: {e}"))?;
+        if &header[0..8] != MAGIC {
+            bail!("not a list of block hashes: bad magic number");
+        }
+        let version = u16::from_be_bytes(header[8..10].try_into()?);
+        if version != VERSION {
+            bail!("unsupported block hashes version {version} (supported version: {VERSION})");
+        }
+
+        let mut hashes = Self::new(
+            BlockHash::try_from(header[10])?,
+            u64::from_be_bytes(header[11..19].try_into()?),
+            u64::from_be_bytes(header[19..27].try_into()?),
+        );
+        let count = u64::from_be_bytes(header[27..35].try_into()?);
+
+        src.read_to_end(&mut hashes.digests)?;
+        if hashes.digests.len() as u64 != count * hashes.algorithm.len() as u64 {
+            bail!("corrupted block hashes: {count} digests expected");
+        }
+
+        Ok(hashes)
+    }
+}
+
+// merge a changed block into ranges of consecutive changed blocks (first, last)
+pub fn add_change(ranges: &mut Vec<(u64, u64)>, block: u64) {
+    match ranges.last_mut() {
+        Some((_, last)) if *last + 1 == block => *last = block,
+        _ => ranges.push((block, block)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let dir = TempDir::new("cbt");
+        let path = dir.join("source.cbt");
+
+        for algorithm in [BlockHash::Xxh3, BlockHash::Blake3] {
+            let mut hashes = BlockHashes::new(algorithm, 4096, 3 * 4096);
+            for i in 0..3u8 {
+                hashes.push(&algorithm.digest(&[i; 4096]));
+            }
+            hashes.write(&path)?;
+
+            let read = BlockHashes::read(&path)?;
+            assert_eq!(read, hashes);
+            assert_eq!(read.get(1), Some(algorithm.digest(&[1; 4096]).as_slice()));
+            assert_eq!(read.get(3), None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes() {
+        let mut ranges = Vec::new();
+        for block in [2, 3, 4, 8, 10, 11] {
+            add_change(&mut ranges, block);
+        }
+        assert_eq!(ranges, vec![(2, 4), (8, 8), (10, 11)]);
+    }
+}
@@ -0,0 +1,128 @@
+// content-defined chunking: chunk boundaries are found with FastCDC on the ordered byte
+// stream instead of every block size bytes, so data shifted by a few bytes still gives the
+// same chunks and can be deduplicated
+use fastcdc::v2020::{AVERAGE_MAX, AVERAGE_MIN, FastCDC};
+
+// chunks average the block size, and are between a quarter and 4 times of it
+pub fn chunk_sizes(block_size: usize) -> (usize, usize, usize) {
+    (block_size / 4, block_size, block_size * 4)
+}
+
+// block sizes which give chunk sizes FastCDC accepts
+pub fn check_block_size(block_size: usize) -> anyhow::Result<()> {
+    if !(AVERAGE_MIN as usize..=AVERAGE_MAX as usize).contains(&block_size) {
+        anyhow::bail!(
+            "content-defined chunking needs a block size between {AVERAGE_MIN} and {AVERAGE_MAX}"
+        );
+    }
+    Ok(())
+}
+
+// cuts a stream given in pieces of any size into content-defined chunks
+#[derive(Debug)]
+pub struct Chunker {
+    // data not cut yet
+    pending: Vec<u8>,
+
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl Chunker {
+    pub fn new(block_size: usize) -> Self {
+        let (min_size, avg_size, max_size) = chunk_sizes(block_size);
+
+        Self {
+            pending: Vec::with_capacity(2 * max_size),
+            min_size,
+            avg_size,
+            max_size,
+        }
+    }
+
+    // add data to the stream, returning chunks which can't change with data coming next
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+
+        // a cut point is always found within max size bytes, so it's final once they're here
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while self.pending.len() - start >= self.max_size {
+            let len = self.cut(&self.pending[start..start + self.max_size]);
+            chunks.push(self.pending[start..start + len].to_vec());
+            start += len;
+        }
+
+        self.pending.drain(..start);
+        chunks
+    }
+
+    // cut what's left at the end of the stream
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        FastCDC::new(
+            &self.pending,
+            self.min_size as u32,
+            self.avg_size as u32,
+            self.max_size as u32,
+        )
+        .map(|chunk| self.pending[chunk.offset..chunk.offset + chunk.length].to_vec())
+        .collect()
+    }
+
+    // length of the first chunk of data
+    fn cut(&self, data: &[u8]) -> usize {
+        FastCDC::new(
+            data,
+            self.min_size as u32,
+            self.avg_size as u32,
+            self.max_size as u32,
+        )
+        .next()
+        .map_or(data.len(), |chunk| chunk.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    fn chunk(data: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(4096);
+        let mut chunks: Vec<Vec<u8>> = data.chunks(piece).flat_map(|p| chunker.push(p)).collect();
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn streaming() {
+        // pseudo random data, so cut points are spread
+        let data = random_bytes(1 << 20);
+
+        // cut points don't depend on how the stream is given
+        let chunks = chunk(&data, 4096);
+        assert_eq!(chunk(&data, 1000), chunks);
+        assert_eq!(chunk(&data, data.len()), chunks);
+        assert_eq!(chunks.concat(), data);
+
+        let (min, _, max) = chunk_sizes(4096);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= min));
+        assert!(chunks.iter().all(|c| c.len() <= max));
+    }
+
+    #[test]
+    fn shifted() {
+        let data = random_bytes(1 << 20);
+        let chunks = chunk(&data, 4096);
+
+        // inserting a few bytes only changes the chunks around them
+        let mut shifted = data[..1000].to_vec();
+        shifted.extend_from_slice(b"shift");
+        shifted.extend_from_slice(&data[1000..]);
+        let shifted = chunk(&shifted, 4096);
+
+        let common = shifted.iter().filter(|c| chunks.contains(c)).count();
+        assert!(common >= chunks.len() - 2);
+    }
+}
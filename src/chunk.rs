@@ -1,18 +1,21 @@
-use std::{
-    borrow::Cow,
-    fs::File,
-    io::{BufWriter, Write},
-};
+use std::{borrow::Cow, io::Write};
+
+use anyhow::{anyhow, bail};
+use xxhash_rust::xxh3::xxh3_128;
 
-use lz4::block::compress;
-// use xxhash_rust::xxh3::xxh3_128;
+use crate::{
+    compression::{Algorithm, compress},
+    repository::ChunkKey,
+    writer::WriterParams,
+};
 
-use crate::writer::WriterParams;
+// length of what precedes chunk data in a record: data length + chunk type
+pub const RECORD_HEADER_LEN: usize = 8 + 1;
 
 // we can have different types of chunks:
 // - "regular" ones with raw data, optionally compressed
 // - zero chunk meaning we read a block of 0's from the source, so we know what is it
-// - compressed with LZ4
+// - compressed with LZ4 or zstd
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
 #[repr(u8)]
 pub enum ChunkType {
@@ -27,6 +30,51 @@ pub enum ChunkType {
 
     // chunk is built for direct mode (dd)
     DDMode = 3,
+
+    // marks the end of the chunk stream, before the index and footer
+    End = 4,
+
+    // zero block in sparse dd mode: nothing is written, output is seeked over
+    Hole = 5,
+
+    // run of consecutive zero blocks: data is the number of blocks
+    ZeroRun = 6,
+
+    // chunk is compressed with zstd
+    Zstd = 7,
+
+    // same content as a previous block: data is the image offset of its record
+    Reference = 8,
+
+    // zero bytes with content-defined chunking: data is their number
+    ZeroExtent = 9,
+
+    // data kept in a repository: data is the key of the chunk there
+    Repository = 10,
+
+    // run of blocks unchanged since the parent image: data is the number of blocks
+    Parent = 11,
+}
+
+impl TryFrom<u8> for ChunkType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ChunkType::FullOfZeros),
+            1 => Ok(ChunkType::Raw),
+            2 => Ok(ChunkType::Compressed),
+            3 => Ok(ChunkType::DDMode),
+            4 => Ok(ChunkType::End),
+            6 => Ok(ChunkType::ZeroRun),
+            7 => Ok(ChunkType::Zstd),
+            8 => Ok(ChunkType::Reference),
+            9 => Ok(ChunkType::ZeroExtent),
+            10 => Ok(ChunkType::Repository),
+            11 => Ok(ChunkType::Parent),
+            _ => Err(anyhow!("unknown chunk type {value}")),
+        }
+    }
 }
 
 // define the block structure save to image file
@@ -40,18 +88,138 @@ pub struct Chunk<'a> {
 
     // xxhash3 to implement deduplication
     // optional is case of pure dd-like imaging
-    //pub hash: Option<u128>,
+    pub hash: Option<u128>,
+
+    // blake3 hash naming the chunk in a repository
+    pub key: Option<ChunkKey>,
 
     // data from what was read. When full of zeros, it's None
     data: Option<Cow<'a, [u8]>>,
 }
 
 impl<'a> Chunk<'a> {
-    // write chunk into output file
-    pub fn write(&self, dst: &mut BufWriter<File>) -> anyhow::Result<()> {
+    // the chunk marking the end of the chunk stream
+    pub fn end() -> Self {
+        Self {
+            len: 0,
+            chunk_type: ChunkType::End,
+            hash: None,
+            key: None,
+            data: None,
+        }
+    }
+
+    // a single block full of zeros
+    pub fn zeros() -> Self {
+        Self {
+            len: 0,
+            chunk_type: ChunkType::FullOfZeros,
+            hash: None,
+            key: None,
+            data: None,
+        }
+    }
+
+    // data stored as is
+    pub fn raw(data: &'a [u8]) -> Self {
+        Self {
+            len: data.len(),
+            chunk_type: ChunkType::Raw,
+            hash: None,
+            key: None,
+            data: Some(Cow::Borrowed(data)),
+        }
+    }
+
+    // a run of count zero blocks
+    pub fn zero_run(count: u64) -> Self {
+        Self {
+            len: 8,
+            chunk_type: ChunkType::ZeroRun,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(count.to_be_bytes().to_vec())),
+        }
+    }
+
+    // len bytes not written, output being seeked over
+    pub fn hole(len: usize) -> Self {
+        Self {
+            len,
+            chunk_type: ChunkType::Hole,
+            hash: None,
+            key: None,
+            data: None,
+        }
+    }
+
+    // a run of count blocks taken from the parent image
+    pub fn parent_run(count: u64) -> Self {
+        Self {
+            len: 8,
+            chunk_type: ChunkType::Parent,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(count.to_be_bytes().to_vec())),
+        }
+    }
+
+    // len zero bytes, whatever the number of chunks they come from
+    pub fn zero_extent(len: u64) -> Self {
+        Self {
+            len: 8,
+            chunk_type: ChunkType::ZeroExtent,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(len.to_be_bytes().to_vec())),
+        }
+    }
+
+    // a block already stored in the record at this image offset
+    pub fn reference(offset: u64) -> Self {
+        Self {
+            len: 8,
+            chunk_type: ChunkType::Reference,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(offset.to_be_bytes().to_vec())),
+        }
+    }
+
+    // a chunk stored in a repository
+    pub fn in_repository(key: &ChunkKey) -> Self {
+        Self {
+            len: key.len(),
+            chunk_type: ChunkType::Repository,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(key.to_vec())),
+        }
+    }
+
+    // chunk not borrowing data anymore, so it can be sent to another thread
+    pub fn into_owned(self) -> Chunk<'static> {
+        Chunk {
+            len: self.len,
+            chunk_type: self.chunk_type,
+            hash: self.hash,
+            key: self.key,
+            data: self.data.map(|d| Cow::Owned(d.into_owned())),
+        }
+    }
+
+    // write chunk into output file, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
         // our write is dependant on type
         match self.chunk_type {
-            ChunkType::Raw | ChunkType::Compressed => {
+            ChunkType::Raw
+            | ChunkType::Compressed
+            | ChunkType::Zstd
+            | ChunkType::ZeroRun
+            | ChunkType::ZeroExtent
+            | ChunkType::Reference
+            | ChunkType::Repository
+            | ChunkType::Parent => {
                 // write first length
                 dst.write_all(&self.len.to_be_bytes())?;
 
@@ -59,18 +227,30 @@ impl<'a> Chunk<'a> {
                 dst.write_all(&[self.chunk_type as u8])?;
 
                 // then
-                dst.write_all(&self.data.as_ref().unwrap())?;
+                dst.write_all(self.data.as_ref().unwrap())?;
             }
-            ChunkType::FullOfZeros => {
+            ChunkType::FullOfZeros | ChunkType::End => {
                 dst.write_all(&self.len.to_be_bytes())?;
 
                 // write chunk type
                 dst.write_all(&[self.chunk_type as u8])?;
             }
-            ChunkType::DDMode => dst.write_all(&self.data.as_ref().unwrap())?,
+            ChunkType::DDMode => dst.write_all(self.data.as_ref().unwrap())?,
+
+            // holes are skipped by the writer
+            ChunkType::Hole => (),
         }
 
-        Ok(())
+        Ok(self.encoded_len())
+    }
+
+    // number of bytes written for this chunk
+    pub fn encoded_len(&self) -> usize {
+        match self.chunk_type {
+            ChunkType::DDMode => self.data.as_ref().map_or(0, |d| d.len()),
+            ChunkType::Hole => self.len,
+            _ => RECORD_HEADER_LEN + self.len,
+        }
     }
     // pub fn write(&self, dst: &File, offset: &mut u64, dd: bool) -> anyhow::Result<()> {
     //     // our write is dependant on type
@@ -125,46 +305,77 @@ impl<'a> TryFrom<(&'a [u8], &WriterParams)> for Chunk<'a> {
     fn try_from(value: (&'a [u8], &WriterParams)) -> Result<Self, Self::Error> {
         let (data, params) = value;
 
-        // if dd mode, we want raw data
-        if params.dd {
+        // if dd mode, we want raw data, except for zero blocks if output is sparse
+        if params.dd && params.sparse && is_zeros(data) {
+            Ok(Self::hole(data.len()))
+        } else if params.dd {
             Ok(Self {
                 len: 0,
                 chunk_type: ChunkType::DDMode,
-                // hash: None,
+                hash: None,
+                key: None,
                 data: Some(Cow::Borrowed(data)),
             })
-        } else if params.compress {
-            let compressed = compress(&data, None, false)?;
-            Ok(Self {
-                len: compressed.len(),
-                chunk_type: ChunkType::Compressed,
-                // hash: Some(xxh3_128(&compressed)),
-                data: Some(Cow::Owned(compressed)),
-            })
+        } else if is_zeros(data) {
+            // chunk contains only the chunk_type here
+            Ok(Self::zeros())
         } else {
-            // test for zeros
-            if is_zeros(data) {
-                // chunk contains only the chunk_type here
-                Ok(Self {
-                    len: 0,
-                    chunk_type: ChunkType::FullOfZeros,
-                    // hash: None,
-                    data: None,
-                })
+            let mut chunk = if params.compress {
+                Self::compressed(data, params)?
             } else {
-                Ok(Self {
-                    len: data.len(),
-                    chunk_type: ChunkType::Raw,
-                    // hash: Some(xxh3_128(data)),
-                    data: Some(Cow::Borrowed(data)),
-                })
+                Self::raw(data)
+            };
+
+            // identical blocks are found by their hash
+            if params.dedup {
+                chunk.hash = Some(xxh3_128(data));
             }
+            if params.repository.is_some() {
+                chunk.key = Some(*blake3::hash(data).as_bytes());
+            }
+
+            Ok(chunk)
+        }
+    }
+}
+
+impl<'a> Chunk<'a> {
+    // data compressed, or as is if compression doesn't save enough
+    fn compressed(data: &'a [u8], params: &WriterParams) -> anyhow::Result<Self> {
+        let chunk_type = match params.algorithm {
+            Algorithm::Lz4 => ChunkType::Compressed,
+            Algorithm::Zstd => ChunkType::Zstd,
+            Algorithm::Gzip => bail!("gzip can only be used to compress dd output"),
+        };
+        let compressed = compress(
+            data,
+            params.algorithm,
+            params.level,
+            params.dictionary.as_deref(),
+        )?;
+
+        // incompressible data is kept as is
+        if !saves_enough(data.len(), compressed.len(), params.min_saving) {
+            return Ok(Self::raw(data));
         }
+
+        Ok(Self {
+            len: compressed.len(),
+            chunk_type,
+            hash: None,
+            key: None,
+            data: Some(Cow::Owned(compressed)),
+        })
     }
 }
 
+// true if compressed data is at least min_saving percent smaller than original data
+fn saves_enough(len: usize, compressed_len: usize, min_saving: u8) -> bool {
+    compressed_len < len && (len - compressed_len) * 100 >= len * min_saving as usize
+}
+
 // test if data slice is full of zeros
-fn is_zeros(data: &[u8]) -> bool {
+pub fn is_zeros(data: &[u8]) -> bool {
     // all() is short circuit => will stop at the first non-0 byte
     data.iter().all(|b| *b == 0)
 }
@@ -176,31 +387,87 @@ mod tests {
     #[test]
     fn try_from() -> anyhow::Result<()> {
         // dd mode
+        let params = WriterParams {
+            dd: true,
+            ..Default::default()
+        };
         let bytes = vec![0xFF; 10];
-        let chunk = Chunk::try_from((bytes.as_slice(), true, false))?;
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
 
         assert_eq!(chunk.chunk_type, ChunkType::DDMode);
-        assert!(chunk.hash.is_none());
-        assert!(chunk.len.is_none());
-        assert_eq!(chunk.data, bytes);
+        assert_eq!(chunk.len, 0);
+        assert_eq!(chunk.data.unwrap().as_ref(), bytes.as_slice());
+
+        // sparse dd mode
+        let params = WriterParams {
+            dd: true,
+            sparse: true,
+            ..Default::default()
+        };
+        let bytes = vec![0u8; 4096];
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
+
+        assert_eq!(chunk.chunk_type, ChunkType::Hole);
+        assert_eq!(chunk.encoded_len(), 4096);
+        assert!(chunk.data.is_none());
 
         // compressed mode
-        let bytes = b"hello world".to_vec();
-        let chunk = Chunk::try_from((bytes.as_slice(), false, true))?;
+        let params = WriterParams {
+            compress: true,
+            ..Default::default()
+        };
+        let bytes = b"hello world".repeat(100);
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
 
         assert_eq!(chunk.chunk_type, ChunkType::Compressed);
+        assert!(chunk.len < bytes.len());
         assert_eq!(
-            chunk.hash.unwrap(),
-            196948728345034857295869450085786825685u128
-        );
-        assert_eq!(chunk.len.unwrap(), 12);
-        assert_eq!(
-            chunk.data,
-            vec![
-                0xb0, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x77, 0x6f, 0x72, 0x6c, 0x64
-            ]
+            lz4::block::decompress(&chunk.data.unwrap(), Some(1100))?,
+            bytes
         );
 
+        // compressed data bigger than original is stored raw
+        let bytes = b"hello world".to_vec();
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
+
+        assert_eq!(chunk.chunk_type, ChunkType::Raw);
+        assert_eq!(chunk.data.unwrap().as_ref(), bytes.as_slice());
+
+        // and so is data not compressed enough
+        let bytes: Vec<u8> = b"hello world".repeat(100);
+        let params = WriterParams {
+            compress: true,
+            min_saving: 99,
+            ..Default::default()
+        };
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
+        assert_eq!(chunk.chunk_type, ChunkType::Raw);
+
+        // zero blocks are detected before compressing
+        let zeros = vec![0u8; 4096];
+        let chunk = Chunk::try_from((zeros.as_slice(), &params))?;
+        assert_eq!(chunk.chunk_type, ChunkType::FullOfZeros);
+
+        // zstd compressed mode
+        let params = WriterParams {
+            compress: true,
+            algorithm: Algorithm::Zstd,
+            level: Some(19),
+            ..Default::default()
+        };
+        let bytes = b"hello world".repeat(100);
+        let chunk = Chunk::try_from((bytes.as_slice(), &params))?;
+
+        assert_eq!(chunk.chunk_type, ChunkType::Zstd);
+        assert_eq!(zstd::bulk::decompress(&chunk.data.unwrap(), 1100)?, bytes);
+
+        // zero block
+        let bytes = vec![0u8; 4096];
+        let chunk = Chunk::try_from((bytes.as_slice(), &WriterParams::default()))?;
+
+        assert_eq!(chunk.chunk_type, ChunkType::FullOfZeros);
+        assert!(chunk.data.is_none());
+
         Ok(())
     }
 }
@@ -0,0 +1,268 @@
+// compression algorithms: LZ4 is fast, zstd gives much better ratios at higher levels and gzip
+// is only there for dd output readable by stock tools
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Write},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use clap::ValueEnum;
+use lz4::block::CompressionMode;
+use serde::Serialize;
+use zstd::{
+    bulk::{Compressor, Decompressor},
+    dict::{DecoderDictionary, EncoderDictionary},
+};
+
+use crate::chunk::is_zeros;
+
+// levels accepted for each algorithm
+pub const LZ4_LEVELS: RangeInclusive<i32> = 1..=12;
+pub const ZSTD_LEVELS: RangeInclusive<i32> = 1..=22;
+pub const GZIP_LEVELS: RangeInclusive<i32> = 1..=9;
+
+// maximum size of a trained dictionary, zstd default one
+const MAX_DICTIONARY_SIZE: usize = 112640;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    // LZ4 block format, no size prefix
+    #[default]
+    Lz4,
+
+    // zstd frame
+    Zstd,
+
+    // gzip member
+    Gzip,
+}
+
+impl Algorithm {
+    // check level is in the range supported by the algorithm
+    pub fn check_level(&self, level: i32) -> anyhow::Result<()> {
+        let levels = match self {
+            Algorithm::Lz4 => LZ4_LEVELS,
+            Algorithm::Zstd => ZSTD_LEVELS,
+            Algorithm::Gzip => GZIP_LEVELS,
+        };
+
+        if !levels.contains(&level) {
+            bail!(
+                "{self} level must be between {} and {}",
+                levels.start(),
+                levels.end()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Lz4 => write!(f, "lz4"),
+            Algorithm::Zstd => write!(f, "zstd"),
+            Algorithm::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+// zstd dictionary trained on blocks of the source, so redundancy between blocks isn't lost
+// when compressing each block independently
+pub struct Dictionary {
+    // dictionary as stored in the image
+    raw: Vec<u8>,
+
+    // prepared once, instead of for each block
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    pub fn new(raw: Vec<u8>, level: Option<i32>) -> Self {
+        let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+
+        Self {
+            encoder: EncoderDictionary::copy(&raw, level),
+            decoder: DecoderDictionary::copy(&raw),
+            raw,
+        }
+    }
+
+    // train a dictionary on the first sample_size bytes of the source, zero blocks excluded
+    pub fn train(
+        path: &Path,
+        block_size: usize,
+        sample_size: u64,
+        level: Option<i32>,
+    ) -> anyhow::Result<Self> {
+        let mut src = File::open(path)
+            .with_context(|| format!("unable to open {}", path.display()))?
+            .take(sample_size);
+
+        let mut samples = Vec::new();
+        loop {
+            let mut block = Vec::with_capacity(block_size);
+            (&mut src).take(block_size as u64).read_to_end(&mut block)?;
+
+            if block.is_empty() {
+                break;
+            }
+            if !is_zeros(&block) {
+                samples.push(block);
+            }
+        }
+
+        let raw = zstd::dict::from_samples(&samples, MAX_DICTIONARY_SIZE).map_err(|e| {
+            anyhow::anyhow!(
+                "unable to train dictionary on {} blocks: {e}",
+                samples.len()
+            )
+        })?;
+
+        Ok(Self::new(raw, level))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    // write dictionary section, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        dst.write_all(&self.raw)?;
+        Ok(self.raw.len())
+    }
+
+    // read a dictionary section of len bytes, only used for decompression
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        let mut raw = vec![0u8; len as usize];
+        src.read_exact(&mut raw)
+            .map_err(|e| anyhow::anyhow!("corrupted dictionary: {e}"))?;
+
+        Ok(Self::new(raw, None))
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dictionary({} bytes)", self.raw.len())
+    }
+}
+
+// compress data, using the algorithm default level if none is given
+// for LZ4, a level selects the high compression mode. A dictionary is only used by zstd
+pub fn compress(
+    data: &[u8],
+    algorithm: Algorithm,
+    level: Option<i32>,
+    dictionary: Option<&Dictionary>,
+) -> anyhow::Result<Vec<u8>> {
+    let compressed = match algorithm {
+        Algorithm::Lz4 => {
+            let mode = level.map(CompressionMode::HIGHCOMPRESSION);
+            lz4::block::compress(data, mode, false)?
+        }
+        Algorithm::Zstd => match dictionary {
+            Some(dictionary) => {
+                Compressor::with_prepared_dictionary(&dictionary.encoder)?.compress(data)?
+            }
+            None => zstd::bulk::compress(data, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?,
+        },
+        Algorithm::Gzip => bail!("gzip only compresses dd output streams"),
+    };
+
+    Ok(compressed)
+}
+
+// decompress data which is at most max_len bytes once decompressed
+pub fn decompress(
+    data: &[u8],
+    algorithm: Algorithm,
+    max_len: usize,
+    dictionary: Option<&Dictionary>,
+) -> anyhow::Result<Vec<u8>> {
+    let decompressed = match algorithm {
+        Algorithm::Lz4 => lz4::block::decompress(data, Some(max_len as i32))?,
+        Algorithm::Zstd => match dictionary {
+            Some(dictionary) => Decompressor::with_prepared_dictionary(&dictionary.decoder)?
+                .decompress(data, max_len)?,
+            None => zstd::bulk::decompress(data, max_len)?,
+        },
+        Algorithm::Gzip => bail!("gzip only compresses dd output streams"),
+    };
+
+    Ok(decompressed)
+}
+
+// worst case size of compressed data, whatever the algorithm
+pub fn max_compressed_len(len: usize) -> usize {
+    let lz4 = len + len / 255 + 16;
+    lz4.max(zstd::zstd_safe::compress_bound(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let data = b"hello world".repeat(1000);
+
+        for algorithm in [Algorithm::Lz4, Algorithm::Zstd] {
+            for level in [None, Some(1), Some(9)] {
+                let compressed = compress(&data, algorithm, level, None)?;
+                assert!(compressed.len() < data.len());
+                assert!(compressed.len() <= max_compressed_len(data.len()));
+                assert_eq!(decompress(&compressed, algorithm, data.len(), None)?, data);
+            }
+        }
+
+        assert!(compress(&data, Algorithm::Gzip, None, None).is_err());
+
+        assert!(Algorithm::Zstd.check_level(19).is_ok());
+        assert!(Algorithm::Lz4.check_level(19).is_err());
+        assert!(Algorithm::Zstd.check_level(0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary() -> anyhow::Result<()> {
+        // blocks sharing the same structure with different values
+        let blocks: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| {
+                (0..64)
+                    .map(|j| format!("inode {:08} owner {:04} mode 0644\n", i * 64 + j, i % 7))
+                    .flat_map(|s| s.into_bytes())
+                    .take(4096)
+                    .collect()
+            })
+            .collect();
+
+        let dir = TempDir::new("dict");
+        let path = dir.join("source.bin");
+        std::fs::write(&path, blocks.concat())?;
+
+        let dictionary = Dictionary::train(&path, 4096, 1 << 20, Some(3))?;
+        let with = compress(&blocks[100], Algorithm::Zstd, Some(3), Some(&dictionary))?;
+        let without = compress(&blocks[100], Algorithm::Zstd, Some(3), None)?;
+        assert!(with.len() < without.len());
+
+        // reading it back from its section
+        let mut section = Vec::new();
+        let n = dictionary.write(&mut section)?;
+        let read = Dictionary::read(&mut section.as_slice(), n as u64)?;
+        assert_eq!(
+            decompress(&with, Algorithm::Zstd, 4096, Some(&read))?,
+            blocks[100]
+        );
+        assert!(decompress(&with, Algorithm::Zstd, 4096, None).is_err());
+
+        Ok(())
+    }
+}
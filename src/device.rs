@@ -8,6 +8,7 @@ use anyhow::anyhow;
 
 pub struct Device;
 
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum DeviceType {
     HDD,
     SSD,
@@ -42,6 +43,7 @@ impl Device {
     }
 
     // try to detect device type
+    #[allow(dead_code)]
     pub fn r#type(name: &str) -> DeviceType {
         let base = format!("/sys/block/{}", name);
 
@@ -0,0 +1,194 @@
+// footer written at the very end of every non-dd image
+//
+// it's a directory of the sections written after the chunk stream, so readers can seek
+// directly to them. Layout (big-endian):
+//
+// (section kind (1) | offset (8) | length (8)) * count | count (4) | magic (8)
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, bail};
+
+// identifies the end of a dimg image
+pub const FOOTER_MAGIC: &[u8; 8] = b"DIMGFOOT";
+
+// length of a section entry
+const SECTION_LEN: usize = 1 + 8 + 8;
+
+// length of the fixed tail: count + magic
+const TAIL_LEN: usize = 4 + 8;
+
+// all sections we know about. Unknown kinds are kept but ignored by readers
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum SectionKind {
+    // block number -> file offset table
+    Index = 1,
+
+    // case metadata
+    Metadata = 2,
+
+    // acquisition outcome and digests
+    Trailer = 3,
+
+    // zstd dictionary used to compress chunks
+    Dictionary = 4,
+
+    // path of the repository holding data chunks of a manifest
+    Repository = 5,
+
+    // blake3 digest of each block, for incremental images made from this one
+    BlockHashes = 6,
+
+    // digests of every window of the source
+    HashWindows = 7,
+
+    // stored level of the Merkle tree over blocks
+    Merkle = 8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Section {
+    // raw section kind
+    pub kind: u8,
+
+    // offset of the section from the beginning of the image
+    pub offset: u64,
+
+    // section length in bytes
+    pub len: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Footer {
+    pub sections: Vec<Section>,
+}
+
+impl Footer {
+    // add a new section to the directory
+    pub fn push(&mut self, kind: SectionKind, offset: u64, len: u64) {
+        self.sections.push(Section {
+            kind: kind as u8,
+            offset,
+            len,
+        });
+    }
+
+    // find a section by its kind
+    pub fn section(&self, kind: SectionKind) -> Option<&Section> {
+        self.sections.iter().find(|s| s.kind == kind as u8)
+    }
+
+    // move to the section of this kind if any, returning its length
+    pub fn seek_section<R: Seek>(
+        &self,
+        src: &mut R,
+        kind: SectionKind,
+    ) -> anyhow::Result<Option<u64>> {
+        match self.section(kind) {
+            Some(section) => {
+                src.seek(SeekFrom::Start(section.offset))?;
+                Ok(Some(section.len))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // write footer into output file, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        for section in &self.sections {
+            dst.write_all(&[section.kind])?;
+            dst.write_all(&section.offset.to_be_bytes())?;
+            dst.write_all(&section.len.to_be_bytes())?;
+        }
+
+        dst.write_all(&(self.sections.len() as u32).to_be_bytes())?;
+        dst.write_all(FOOTER_MAGIC)?;
+
+        Ok(self.sections.len() * SECTION_LEN + TAIL_LEN)
+    }
+
+    // read footer from the end of an image
+    pub fn read<R: Read + Seek>(src: &mut R) -> anyhow::Result<Self> {
+        let image_len = src.seek(SeekFrom::End(0))?;
+        if image_len < TAIL_LEN as u64 {
+            bail!("image too short to hold a footer");
+        }
+
+        // fixed tail first
+        let mut tail = [0u8; TAIL_LEN];
+        src.seek(SeekFrom::End(-(TAIL_LEN as i64)))?;
+        src.read_exact(&mut tail)?;
+
+        if &tail[4..] != FOOTER_MAGIC {
+            bail!("no footer found: image is truncated or was not completed");
+        }
+        let count = u32::from_be_bytes(tail[0..4].try_into()?) as usize;
+
+        // then the section directory located before it
+        let dir_len = (count * SECTION_LEN + TAIL_LEN) as u64;
+        if image_len < dir_len {
+            bail!("corrupted footer: {count} sections don't fit in image");
+        }
+        src.seek(SeekFrom::End(-(dir_len as i64)))?;
+
+        let mut sections = Vec::with_capacity(count);
+        let mut buf = [0u8; SECTION_LEN];
+        for _ in 0..count {
+            src.read_exact(&mut buf)?;
+
+            let section = Section {
+                kind: buf[0],
+                offset: u64::from_be_bytes(buf[1..9].try_into()?),
+                len: u64::from_be_bytes(buf[9..17].try_into()?),
+            };
+            if section
+                .offset
+                .checked_add(section.len)
+                .is_none_or(|end| end > image_len - dir_len)
+            {
+                return Err(anyhow!(
+                    "corrupted footer: section {} is out of image bounds",
+                    section.kind
+                ));
+            }
+            sections.push(section);
+        }
+
+        Ok(Self { sections })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        // some fake chunk stream before the footer
+        let mut image = vec![0u8; 1000];
+
+        let mut footer = Footer::default();
+        footer.push(SectionKind::Index, 100, 250);
+        let n = footer.write(&mut image)?;
+        assert_eq!(n, SECTION_LEN + TAIL_LEN);
+
+        let read = Footer::read(&mut Cursor::new(&image))?;
+        assert_eq!(read, footer);
+        assert_eq!(read.section(SectionKind::Index).unwrap().offset, 100);
+
+        // truncated image
+        image.pop();
+        assert!(Footer::read(&mut Cursor::new(&image)).is_err());
+
+        // section whose end overflows
+        let mut image = vec![0u8; 1000];
+        let mut footer = Footer::default();
+        footer.push(SectionKind::Index, 100, u64::MAX);
+        footer.write(&mut image)?;
+        assert!(Footer::read(&mut Cursor::new(&image)).is_err());
+
+        Ok(())
+    }
+}
@@ -1,9 +1,403 @@
-// all functions for xxhash3 or blake3
+// all functions for xxhash3 or blake3, and digests of the whole source
+use std::{
+    fmt, fs, mem,
+    path::Path,
+    sync::{
+        Arc,
+        mpsc::{self, SyncSender},
+    },
+    thread::{self, JoinHandle},
+};
 
-use xxhash_rust::xxh3::xxh3_128;
+use clap::ValueEnum;
+use md5::Md5;
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use xxhash_rust::xxh3::Xxh3;
 
-// compute the xxhash3-128 of zeroed block of data
-pub fn zeroed_hash(block_size: usize) -> u128 {
-    let bytes = vec![0u8; block_size];
-    xxh3_128(&bytes)
+use crate::{
+    merkle::{MerkleBuilder, MerkleTree},
+    window::{HashWindows, WindowHasher},
+};
+
+// below this, Blake3 is faster on a single core than spread over the thread pool
+const RAYON_MIN_LEN: usize = 128 * 1024;
+
+// data is given to pool hashers in groups this big, so Blake3 has enough to spread over cores
+const GROUP_SIZE: usize = 4 * 1024 * 1024;
+
+// groups queued for each pool hasher before update waits for the slowest one
+const QUEUE_LEN: usize = 4;
+
+// data shared by all pool hashers
+type Group = Arc<Vec<u8>>;
+
+// algorithms a digest of the source can be computed with
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+
+    // xxh3-128, as printed by xxh128sum
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 6] = [
+        HashAlgorithm::Md5,
+        HashAlgorithm::Sha1,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Xxh3,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    // algorithm of a name, as found in trailers or given to --expect
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.name() == name)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// a running hash
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Blake3(h) if data.len() >= RAYON_MIN_LEN => {
+                h.update_rayon(data);
+            }
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    // lowercase hex digest
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Md5(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha1(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_string(),
+            Hasher::Xxh3(h) => format!("{:032x}", h.digest128()),
+        }
+    }
+}
+
+// hashes calculated over the whole source, when asked for, all in the same pass
+#[derive(Default)]
+pub struct Hashes {
+    hashers: Vec<(HashAlgorithm, Hasher)>,
+}
+
+impl Hashes {
+    pub fn new(algorithms: &[HashAlgorithm]) -> Self {
+        Self {
+            hashers: algorithms.iter().map(|a| (*a, Hasher::new(*a))).collect(),
+        }
+    }
+
+    // feed hashers with next block of data
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    // all final hashes as (algorithm, digest)
+    pub fn digests(self) -> Vec<(&'static str, String)> {
+        self.hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm.name(), hasher.finalize()))
+            .collect()
+    }
+}
+
+// same as Hashes, but each algorithm runs on its own thread and Blake3 is tree hashed over the
+// rayon pool, so hashing doesn't hold back the thread feeding it. Digests are the same as if
+// the stream was hashed sequentially. Hash windows and the Merkle tree are built the same way
+pub struct HashPool {
+    // data not given to hashers yet
+    pending: Vec<u8>,
+
+    hashers: Vec<PoolHasher<String>>,
+    algorithms: Vec<HashAlgorithm>,
+
+    windows: Option<PoolHasher<HashWindows>>,
+    merkle: Option<PoolHasher<MerkleTree>>,
+}
+
+// a pool thread, fed with groups, giving back what it made of them
+type PoolHasher<T> = (SyncSender<Group>, JoinHandle<T>);
+
+// what a pool gives back once all data was hashed
+pub struct PoolOutput {
+    pub digests: Vec<(&'static str, String)>,
+    pub windows: Option<HashWindows>,
+    pub merkle: Option<MerkleTree>,
+}
+
+impl HashPool {
+    pub fn new(algorithms: &[HashAlgorithm]) -> Self {
+        let hashers = algorithms
+            .iter()
+            .map(|algorithm| {
+                spawn_hasher(Hasher::new(*algorithm), Hasher::update, Hasher::finalize)
+            })
+            .collect();
+
+        Self {
+            pending: Vec::new(),
+            hashers,
+            algorithms: algorithms.to_vec(),
+            windows: None,
+            merkle: None,
+        }
+    }
+
+    // also hash every window of this size
+    pub fn with_windows(mut self, size: u64, algorithms: &[HashAlgorithm]) -> Self {
+        let hasher = WindowHasher::new(size, algorithms);
+        self.windows = Some(spawn_hasher(
+            hasher,
+            WindowHasher::update,
+            WindowHasher::finish,
+        ));
+        self
+    }
+
+    // also build a Merkle tree over leaves of this size
+    pub fn with_merkle(mut self, leaf_size: u64) -> Self {
+        let builder = MerkleBuilder::new(leaf_size);
+        self.merkle = Some(spawn_hasher(
+            builder,
+            MerkleBuilder::update,
+            MerkleBuilder::finish,
+        ));
+        self
+    }
+
+    // feed hashers with next block of data
+    pub fn update(&mut self, data: &[u8]) {
+        if self.hashers.is_empty() && self.windows.is_none() && self.merkle.is_none() {
+            return;
+        }
+
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= GROUP_SIZE {
+            self.send();
+        }
+    }
+
+    // all final hashes as (algorithm, digest), once hashers are done with all data
+    pub fn digests(self) -> Vec<(&'static str, String)> {
+        self.finish().digests
+    }
+
+    // digests, windows and Merkle tree, once hashers are done with all data
+    pub fn finish(mut self) -> PoolOutput {
+        if !self.pending.is_empty() {
+            self.send();
+        }
+
+        PoolOutput {
+            digests: self
+                .hashers
+                .into_iter()
+                .zip(self.algorithms)
+                .map(|(hasher, algorithm)| (algorithm.name(), join_hasher(hasher)))
+                .collect(),
+            windows: self.windows.map(join_hasher),
+            merkle: self.merkle.map(join_hasher),
+        }
+    }
+
+    // give pending data to every hasher
+    fn send(&mut self) {
+        let group = Arc::new(mem::replace(
+            &mut self.pending,
+            Vec::with_capacity(GROUP_SIZE),
+        ));
+        let windows = self.windows.iter().map(|(tx, _)| tx);
+        let merkle = self.merkle.iter().map(|(tx, _)| tx);
+        for tx in self
+            .hashers
+            .iter()
+            .map(|(tx, _)| tx)
+            .chain(windows)
+            .chain(merkle)
+        {
+            tx.send(Arc::clone(&group))
+                .expect("hasher thread stopped early");
+        }
+    }
+}
+
+// run a hasher on its own thread, which updates it with every group it's given and finishes
+// it once they're all received
+fn spawn_hasher<H, T>(mut hasher: H, update: fn(&mut H, &[u8]), finish: fn(H) -> T) -> PoolHasher<T>
+where
+    H: Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::sync_channel::<Group>(QUEUE_LEN);
+    let handle = thread::spawn(move || {
+        for group in rx {
+            update(&mut hasher, &group);
+        }
+        finish(hasher)
+    });
+    (tx, handle)
+}
+
+// wait for a hasher to be done with all groups
+fn join_hasher<T>((tx, handle): PoolHasher<T>) -> T {
+    drop(tx);
+    handle.join().expect("hasher thread panicked")
+}
+
+// lowercase hex form of bytes, such as keys and Merkle hashes
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// print digests of the source: alone if there's only one, otherwise prefixed by algorithm
+pub fn print_digests(digests: &[(String, String)]) {
+    match digests {
+        [(_, digest)] => println!("{digest}"),
+        _ => {
+            for (algorithm, digest) in digests {
+                println!("{algorithm}: {digest}");
+            }
+        }
+    }
+}
+
+// write digests next to output, one OUTPUT.ALGO file each, as sha256sum and friends would
+// print them for the file named
+pub fn write_sum_files(
+    output: &Path,
+    digests: &[(String, String)],
+    name: &Path,
+) -> anyhow::Result<()> {
+    for (algorithm, digest) in digests {
+        let mut path = output.as_os_str().to_owned();
+        path.push(format!(".{algorithm}"));
+
+        fs::write(&path, format!("{digest}  {}\n", name.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let mut hashes = Hashes::new(&HashAlgorithm::ALL);
+        hashes.update(b"hello ");
+        hashes.update(b"world");
+
+        // same as md5sum, sha1sum, sha256sum, sha512sum, b3sum and xxh128sum
+        let digests = hashes.digests();
+        assert_eq!(
+            digests.iter().map(|(a, _)| *a).collect::<Vec<_>>(),
+            ["md5", "sha1", "sha256", "sha512", "blake3", "xxh3"]
+        );
+        assert_eq!(digests[0].1, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(digests[1].1, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+        assert_eq!(
+            digests[2].1,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert!(digests[3].1.starts_with("309ecc489c12d6eb4cc40f50c902f2b4"));
+        assert_eq!(
+            digests[4].1,
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24"
+        );
+        assert_eq!(digests[5].1, format!("{:032x}", xxh3_128(b"hello world")));
+    }
+
+    #[test]
+    fn pool() {
+        // several groups, and a last one which is not full
+        let data: Vec<u8> = (0..2 * GROUP_SIZE + 12345)
+            .map(|i| ((i * 31) ^ (i >> 9)) as u8)
+            .collect();
+
+        let mut hashes = Hashes::new(&HashAlgorithm::ALL);
+        hashes.update(&data);
+
+        // whatever the pieces the stream is given in
+        let mut pool = HashPool::new(&HashAlgorithm::ALL);
+        data.chunks(32768 + 7).for_each(|p| pool.update(p));
+
+        assert_eq!(pool.digests(), hashes.digests());
+        assert!(HashPool::new(&[]).digests().is_empty());
+
+        // windows and Merkle tree too, even without digests of the whole stream
+        let mut windows = WindowHasher::new(1 << 20, &[HashAlgorithm::Sha256]);
+        windows.update(&data);
+        let mut merkle = MerkleBuilder::new(4096);
+        merkle.update(&data);
+
+        let mut pool = HashPool::new(&[])
+            .with_windows(1 << 20, &[HashAlgorithm::Sha256])
+            .with_merkle(4096);
+        data.chunks(32768 + 7).for_each(|p| pool.update(p));
+        let output = pool.finish();
+
+        assert_eq!(output.windows, Some(windows.finish()));
+        assert_eq!(output.merkle, Some(merkle.finish()));
+    }
 }
@@ -0,0 +1,373 @@
+// image header written at the very beginning of every non-dd image
+//
+// layout (all integers are big-endian, like chunk lengths):
+//
+// magic (4) | format version (2) | header length (4) | block size (8) | source size (8)
+// | flags (4) | dimg version length (1) | dimg version (n)
+// [| parent path length (2) | parent path | parent digest length (1) | parent digest]
+//
+// the compression level is kept in the second byte of flags, 0 meaning default level. The
+// parent is only there for incremental images
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+
+use crate::{compression::Algorithm, hash::HashAlgorithm, parent::ParentRef, writer::WriterParams};
+
+// identifies a dimg image
+pub const MAGIC: &[u8; 4] = b"DIMG";
+
+// bumped each time the on-disk layout changes in a non compatible way:
+//
+// 1: first layout
+// 2: index entries hold the source offset of their chunk
+pub const FORMAT_VERSION: u16 = 2;
+
+// length of the fixed part of the header, before the dimg version string
+const FIXED_LEN: usize = 4 + 2 + 4 + 8 + 8 + 4 + 1;
+
+// flags mapping WriterParams booleans
+const FLAG_COMPRESS: u32 = 1 << 0;
+const FLAG_DD: u32 = 1 << 1;
+const FLAG_SHA256: u32 = 1 << 2;
+const FLAG_BLAKE3: u32 = 1 << 3;
+const FLAG_ZSTD: u32 = 1 << 4;
+const FLAG_DICTIONARY: u32 = 1 << 5;
+const FLAG_CDC: u32 = 1 << 6;
+const FLAG_REPOSITORY: u32 = 1 << 7;
+const FLAG_PARENT: u32 = 1 << 16;
+const FLAG_MD5: u32 = 1 << 17;
+const FLAG_SHA1: u32 = 1 << 18;
+const FLAG_SHA512: u32 = 1 << 19;
+const FLAG_XXH3: u32 = 1 << 20;
+const LEVEL_SHIFT: u32 = 8;
+
+// flags this version knows about, level included
+const KNOWN_FLAGS: u32 = FLAG_COMPRESS
+    | FLAG_DD
+    | FLAG_SHA256
+    | FLAG_BLAKE3
+    | FLAG_ZSTD
+    | FLAG_DICTIONARY
+    | FLAG_CDC
+    | FLAG_REPOSITORY
+    | (0xFF << LEVEL_SHIFT)
+    | FLAG_PARENT
+    | FLAG_MD5
+    | FLAG_SHA1
+    | FLAG_SHA512
+    | FLAG_XXH3;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImageHeader {
+    // format version read from or written to the image
+    pub version: u16,
+
+    // size of the blocks read from the source
+    pub block_size: u64,
+
+    // size of the source device or file in bytes
+    pub source_size: u64,
+
+    // acquisition parameters
+    pub compress: bool,
+    pub dd: bool,
+
+    // digests of the source computed at acquisition time
+    pub hashes: Vec<HashAlgorithm>,
+
+    // compression algorithm, and level or 0 for the algorithm default one
+    pub algorithm: Algorithm,
+    pub level: u8,
+
+    // true if chunks are compressed with the dictionary stored in the image
+    pub dictionary: bool,
+
+    // true if chunks are content-defined, averaging block size, instead of blocks
+    pub cdc: bool,
+
+    // true if data chunks are kept in a repository, the image being a manifest
+    pub repository: bool,
+
+    // version of dimg which created the image
+    pub dimg_version: String,
+
+    // image this one is an increment of
+    pub parent: Option<ParentRef>,
+}
+
+impl ImageHeader {
+    // length of the header once written
+    pub fn encoded_len(&self) -> usize {
+        FIXED_LEN
+            + self.dimg_version.len()
+            + self
+                .parent
+                .as_ref()
+                .map_or(0, |p| 2 + p.path.len() + 1 + p.digest.len())
+    }
+
+    // write header into output file, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        let version = self.dimg_version.as_bytes();
+        let version_len =
+            u8::try_from(version.len()).map_err(|_| anyhow!("dimg version string too long"))?;
+
+        dst.write_all(MAGIC)?;
+        dst.write_all(&self.version.to_be_bytes())?;
+        dst.write_all(&(self.encoded_len() as u32).to_be_bytes())?;
+        dst.write_all(&self.block_size.to_be_bytes())?;
+        dst.write_all(&self.source_size.to_be_bytes())?;
+        dst.write_all(&self.flags().to_be_bytes())?;
+        dst.write_all(&[version_len])?;
+        dst.write_all(version)?;
+
+        if let Some(parent) = &self.parent {
+            let path_len =
+                u16::try_from(parent.path.len()).map_err(|_| anyhow!("parent path too long"))?;
+            let digest_len =
+                u8::try_from(parent.digest.len()).map_err(|_| anyhow!("parent digest too long"))?;
+
+            dst.write_all(&path_len.to_be_bytes())?;
+            dst.write_all(parent.path.as_bytes())?;
+            dst.write_all(&[digest_len])?;
+            dst.write_all(parent.digest.as_bytes())?;
+        }
+
+        Ok(self.encoded_len())
+    }
+
+    // read and check header from the beginning of an image
+    pub fn read<R: Read>(src: &mut R) -> anyhow::Result<Self> {
+        let mut fixed = [0u8; FIXED_LEN];
+        src.read_exact(&mut fixed)
+            .map_err(|e| anyhow!("unable to read image header: {e}"))?;
+
+        if &fixed[0..4] != MAGIC {
+            bail!("not a dimg image: bad magic number");
+        }
+
+        // refuse to go further if we don't know this version
+        let version = u16::from_be_bytes(fixed[4..6].try_into()?);
+        if version != FORMAT_VERSION {
+            bail!(
+                "unsupported image format version {version} (supported version: {FORMAT_VERSION})"
+            );
+        }
+
+        let header_len = u32::from_be_bytes(fixed[6..10].try_into()?) as usize;
+        let block_size = u64::from_be_bytes(fixed[10..18].try_into()?);
+        let source_size = u64::from_be_bytes(fixed[18..26].try_into()?);
+        let flags = u32::from_be_bytes(fixed[26..30].try_into()?);
+        let version_len = fixed[30] as usize;
+
+        // a flag we don't know about would change how the image is read
+        if flags & !KNOWN_FLAGS != 0 {
+            bail!("unsupported image flags {:#x}", flags & !KNOWN_FLAGS);
+        }
+
+        if header_len < FIXED_LEN + version_len {
+            bail!("corrupted image header: inconsistent header length {header_len}");
+        }
+
+        // variable part
+        let mut rest = vec![0u8; header_len - FIXED_LEN];
+        src.read_exact(&mut rest)
+            .map_err(|e| anyhow!("unable to read image header: {e}"))?;
+        let (dimg_version, mut rest) = rest.split_at(version_len);
+
+        let parent = if flags & FLAG_PARENT != 0 {
+            let path = read_string(&mut rest, 2)?;
+            let digest = read_string(&mut rest, 1)?;
+            Some(ParentRef { path, digest })
+        } else {
+            None
+        };
+        if !rest.is_empty() {
+            bail!("corrupted image header: inconsistent header length {header_len}");
+        }
+
+        Ok(Self {
+            version,
+            block_size,
+            source_size,
+            compress: flags & FLAG_COMPRESS != 0,
+            dd: flags & FLAG_DD != 0,
+            hashes: HashAlgorithm::ALL
+                .into_iter()
+                .filter(|a| flags & hash_flag(*a) != 0)
+                .collect(),
+            algorithm: if flags & FLAG_ZSTD != 0 {
+                Algorithm::Zstd
+            } else {
+                Algorithm::Lz4
+            },
+            level: (flags >> LEVEL_SHIFT) as u8,
+            dictionary: flags & FLAG_DICTIONARY != 0,
+            cdc: flags & FLAG_CDC != 0,
+            repository: flags & FLAG_REPOSITORY != 0,
+            dimg_version: String::from_utf8(dimg_version.to_vec())?,
+            parent,
+        })
+    }
+
+    // pack booleans into flags
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+
+        if self.compress {
+            flags |= FLAG_COMPRESS;
+        }
+        if self.dd {
+            flags |= FLAG_DD;
+        }
+        for algorithm in &self.hashes {
+            flags |= hash_flag(*algorithm);
+        }
+        if self.algorithm == Algorithm::Zstd {
+            flags |= FLAG_ZSTD;
+        }
+        if self.dictionary {
+            flags |= FLAG_DICTIONARY;
+        }
+        if self.cdc {
+            flags |= FLAG_CDC;
+        }
+        if self.repository {
+            flags |= FLAG_REPOSITORY;
+        }
+        if self.parent.is_some() {
+            flags |= FLAG_PARENT;
+        }
+        flags |= (self.level as u32) << LEVEL_SHIFT;
+
+        flags
+    }
+}
+
+impl From<&WriterParams> for ImageHeader {
+    fn from(params: &WriterParams) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            block_size: params.block_size as u64,
+            source_size: params.source_size,
+            compress: params.compress,
+            dd: params.dd,
+            hashes: params.hashes.clone(),
+            algorithm: params.algorithm,
+            level: params.level.unwrap_or_default() as u8,
+            dictionary: params.dictionary.is_some(),
+            cdc: params.cdc,
+            repository: params.repository.is_some(),
+            dimg_version: env!("CARGO_PKG_VERSION").to_string(),
+            parent: params.parent.as_ref().map(|p| p.id.clone()),
+        }
+    }
+}
+
+// flag telling a digest was computed with algorithm
+fn hash_flag(algorithm: HashAlgorithm) -> u32 {
+    match algorithm {
+        HashAlgorithm::Md5 => FLAG_MD5,
+        HashAlgorithm::Sha1 => FLAG_SHA1,
+        HashAlgorithm::Sha256 => FLAG_SHA256,
+        HashAlgorithm::Sha512 => FLAG_SHA512,
+        HashAlgorithm::Blake3 => FLAG_BLAKE3,
+        HashAlgorithm::Xxh3 => FLAG_XXH3,
+    }
+}
+
+// string prefixed by its length on len_size bytes, taken from the beginning of buf
+fn read_string(buf: &mut &[u8], len_size: usize) -> anyhow::Result<String> {
+    if buf.len() < len_size {
+        bail!("corrupted image header: truncated parent");
+    }
+    let (len, rest) = buf.split_at(len_size);
+    let len = len.iter().fold(0usize, |n, b| (n << 8) | *b as usize);
+
+    if rest.len() < len {
+        bail!("corrupted image header: truncated parent");
+    }
+    let (s, rest) = rest.split_at(len);
+    *buf = rest;
+
+    Ok(String::from_utf8(s.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> ImageHeader {
+        ImageHeader {
+            version: FORMAT_VERSION,
+            block_size: 32768,
+            source_size: 1 << 30,
+            compress: true,
+            dd: false,
+            hashes: vec![
+                HashAlgorithm::Md5,
+                HashAlgorithm::Sha256,
+                HashAlgorithm::Xxh3,
+            ],
+            algorithm: Algorithm::Zstd,
+            level: 19,
+            dictionary: true,
+            cdc: true,
+            repository: false,
+            dimg_version: "0.1.0".to_string(),
+            parent: Some(ParentRef {
+                path: "/images/previous.img".to_string(),
+                digest: "sha256:0123".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let header = header();
+
+        let mut buf = Vec::new();
+        let n = header.write(&mut buf)?;
+        assert_eq!(n, buf.len());
+        assert_eq!(&buf[0..4], MAGIC);
+
+        let read = ImageHeader::read(&mut buf.as_slice())?;
+        assert_eq!(read, header);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_magic() -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        header().write(&mut buf)?;
+        buf[0] = b'X';
+
+        assert!(ImageHeader::read(&mut buf.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_version() -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        header().write(&mut buf)?;
+        buf[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+
+        let err = ImageHeader::read(&mut buf.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("unsupported image format version"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_flag() -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        header().write(&mut buf)?;
+        buf[26] |= 0x80;
+
+        let err = ImageHeader::read(&mut buf.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("unsupported image flags"));
+        Ok(())
+    }
+}
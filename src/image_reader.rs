@@ -0,0 +1,754 @@
+// streaming decoder for dimg images
+//
+// walks the chunk records written by the writer thread and rebuilds the original
+// blocks: zero chunks are expanded, compressed chunks are decompressed, raw chunks
+// are passed through and references are resolved by seeking back to their target.
+// Manifests have their data chunks read from a repository, and incremental images read their
+// parent along to take unchanged blocks from it.
+use std::{
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow, bail};
+
+use crate::{
+    cdc::chunk_sizes,
+    chunk::{ChunkType, RECORD_HEADER_LEN},
+    compression::{Algorithm, Dictionary, decompress, max_compressed_len},
+    footer::{Footer, SectionKind},
+    hash::hex,
+    header::ImageHeader,
+    index::IndexEntry,
+    parent::{ParentReader, ParentRef},
+    repository::{ChunkKey, Repository},
+};
+
+// a block rebuilt from a chunk record
+#[derive(Debug)]
+pub struct Block {
+    // block number in the source
+    pub number: u64,
+
+    // chunk type the block was stored with
+    pub chunk_type: ChunkType,
+
+    // length of the data stored in the image for this block
+    pub stored_len: usize,
+
+    // original block data
+    pub data: Vec<u8>,
+}
+
+pub struct ImageReader<R: Read + Seek> {
+    // image being decoded
+    src: R,
+
+    // header read when opening the image
+    header: ImageHeader,
+
+    // next block number to decode
+    block: u64,
+
+    // current offset in the image, used for error messages
+    offset: u64,
+
+    // number of bytes of the source rebuilt so far
+    logical_offset: u64,
+
+    // true when end of chunk stream is reached
+    done: bool,
+
+    // zero bytes of a run or extent still to be given back, with the type of their record
+    zero_run: Option<(ChunkType, u64)>,
+
+    // dictionary needed to decompress zstd chunks, if any
+    dictionary: Option<Dictionary>,
+
+    // repository holding data chunks of a manifest
+    repository: Option<Repository>,
+
+    // parent of an incremental image, and blocks of a parent run still to be given back
+    parent: Option<ParentReader>,
+    parent_run: u64,
+
+    // open the parent recorded in the header once decoding starts, unless one was given
+    recorded_parent: bool,
+
+    // block being consumed through the Read implementation
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read + Seek> ImageReader<R> {
+    // read header and get ready to decode the chunk stream
+    pub fn new(mut src: R) -> anyhow::Result<Self> {
+        let header = ImageHeader::read(&mut src)?;
+
+        if header.block_size == 0 {
+            bail!("corrupted image header: block size is 0");
+        }
+
+        Ok(Self {
+            src,
+            offset: header.encoded_len() as u64,
+            header,
+            block: 0,
+            logical_offset: 0,
+            done: false,
+            zero_run: None,
+            dictionary: None,
+            repository: None,
+            parent: None,
+            parent_run: 0,
+            recorded_parent: false,
+            current: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    // use this repository instead of the one recorded in the manifest
+    pub fn set_repository(&mut self, repository: Repository) {
+        self.repository = Some(repository);
+    }
+
+    // read unchanged blocks from this parent instead of the one recorded in the header
+    pub fn set_parent(&mut self, path: &Path) -> anyhow::Result<()> {
+        let recorded = self
+            .header
+            .parent
+            .as_ref()
+            .context("image is not incremental, it has no parent")?;
+        let id = ParentRef {
+            path: path.display().to_string(),
+            digest: recorded.digest.clone(),
+        };
+
+        self.parent = Some(ParentReader::open(&id)?);
+        Ok(())
+    }
+
+    // move to the record of an index entry, to decode from its block on
+    pub fn seek_entry(&mut self, entry: &IndexEntry) -> anyhow::Result<()> {
+        if self.header.parent.is_some() {
+            bail!("blocks of an incremental image can only be decoded in order");
+        }
+
+        self.src.seek(SeekFrom::Start(entry.offset))?;
+        self.offset = entry.offset;
+        self.block = entry.block;
+        self.logical_offset = entry.start;
+        self.done = false;
+        self.zero_run = None;
+        self.current.clear();
+        self.pos = 0;
+
+        Ok(())
+    }
+
+    // decode next record, returns None at the end of the chunk stream
+    pub fn next_block(&mut self) -> anyhow::Result<Option<Block>> {
+        // parent, and its own parents, are checked before being read along, and once read. A
+        // parent which moved is missing or doesn't match, and can still be given with set_parent
+        if self.recorded_parent {
+            self.recorded_parent = false;
+            if let Some(id) = &self.header.parent
+                && self.parent.is_none()
+                && Path::new(&id.path).exists()
+            {
+                self.parent = Some(ParentReader::open(id)?);
+            }
+        }
+
+        let block = self.decode_next()?;
+
+        // parent is read along, so blocks not taken from it are skipped
+        if let (Some(block), Some(parent)) = (&block, &mut self.parent)
+            && block.chunk_type != ChunkType::Parent
+        {
+            io::copy(
+                &mut parent.by_ref().take(block.data.len() as u64),
+                &mut io::sink(),
+            )?;
+        }
+
+        Ok(block)
+    }
+
+    fn decode_next(&mut self) -> anyhow::Result<Option<Block>> {
+        if self.parent_run > 0 {
+            self.parent_run -= 1;
+            let data = self.parent_block()?;
+            return Ok(Some(self.emit(ChunkType::Parent, 0, data)));
+        }
+
+        // a run gives back one zero block at a time
+        if let Some((chunk_type, remaining)) = self.zero_run {
+            let data = self.zero_piece(chunk_type, remaining);
+            return Ok(Some(self.emit(chunk_type, 0, data)));
+        }
+
+        if self.done {
+            return Ok(None);
+        }
+
+        let (chunk_type, stored) = self.read_record(self.offset)?;
+        let len = stored.len();
+        let expected = self.expected_len();
+
+        let data = match chunk_type {
+            ChunkType::End => {
+                self.done = true;
+                if let Some(parent) = &mut self.parent {
+                    parent.finish()?;
+                }
+                return Ok(None);
+            }
+            ChunkType::FullOfZeros => vec![0u8; expected],
+            ChunkType::ZeroRun => {
+                let run = u64::from_be_bytes(stored.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "corrupted zero run at offset {}: bad length {len}",
+                        self.offset
+                    )
+                })?);
+
+                // a run can't go beyond the end of source
+                let remaining = (self.header.source_size.saturating_sub(self.logical_offset))
+                    .div_ceil(self.header.block_size);
+                if run == 0 || run > remaining {
+                    bail!(
+                        "corrupted zero run at offset {}: {run} blocks while {remaining} remain",
+                        self.offset
+                    );
+                }
+
+                let remaining = self.header.source_size - self.logical_offset;
+                self.zero_piece(chunk_type, (run * self.header.block_size).min(remaining))
+            }
+            ChunkType::Parent => {
+                let run = u64::from_be_bytes(stored.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "corrupted parent run at offset {}: bad length {len}",
+                        self.offset
+                    )
+                })?);
+
+                let remaining = (self.header.source_size.saturating_sub(self.logical_offset))
+                    .div_ceil(self.header.block_size);
+                if run == 0 || run > remaining {
+                    bail!(
+                        "corrupted parent run at offset {}: {run} blocks while {remaining} remain",
+                        self.offset
+                    );
+                }
+
+                self.parent_run = run - 1;
+                self.parent_block()?
+            }
+            ChunkType::ZeroExtent => {
+                let extent = u64::from_be_bytes(stored.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "corrupted zero extent at offset {}: bad length {len}",
+                        self.offset
+                    )
+                })?);
+
+                let remaining = self.header.source_size.saturating_sub(self.logical_offset);
+                if extent == 0 || extent > remaining {
+                    bail!(
+                        "corrupted zero extent at offset {}: {extent} bytes while {remaining} remain",
+                        self.offset
+                    );
+                }
+                self.zero_piece(chunk_type, extent)
+            }
+            ChunkType::Reference => {
+                let target = u64::from_be_bytes(stored.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "corrupted reference at offset {}: bad length {len}",
+                        self.offset
+                    )
+                })?);
+
+                // a reference always points back to a record already decoded
+                if target >= self.offset {
+                    bail!(
+                        "corrupted reference at offset {}: points forward to {target}",
+                        self.offset
+                    );
+                }
+
+                let resume = self.src.stream_position()?;
+                self.src.seek(SeekFrom::Start(target))?;
+                let (target_type, target_stored) = self.read_record(target)?;
+                self.src.seek(SeekFrom::Start(resume))?;
+
+                if !matches!(
+                    target_type,
+                    ChunkType::Raw | ChunkType::Compressed | ChunkType::Zstd
+                ) {
+                    bail!(
+                        "corrupted reference at offset {}: points to a {target_type:?} chunk",
+                        self.offset
+                    );
+                }
+                self.decode(target_type, target_stored, target)?
+            }
+            ChunkType::Raw | ChunkType::Compressed | ChunkType::Zstd => {
+                self.decode(chunk_type, stored, self.offset)?
+            }
+            ChunkType::Repository => {
+                let key: ChunkKey = stored.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "corrupted repository chunk at offset {}: bad length {len}",
+                        self.offset
+                    )
+                })?;
+                self.fetch(&key)?
+            }
+            ChunkType::DDMode | ChunkType::Hole => {
+                bail!("unexpected {chunk_type:?} chunk at offset {}", self.offset)
+            }
+        };
+
+        // content-defined chunks are of any size up to the maximum one
+        if self.header.cdc {
+            let remaining = self.header.source_size.saturating_sub(self.logical_offset);
+            if data.is_empty() || data.len() > self.max_len() || data.len() as u64 > remaining {
+                bail!(
+                    "chunk {} at offset {} has {} bytes while {remaining} remain",
+                    self.block,
+                    self.offset,
+                    data.len()
+                );
+            }
+        } else if data.len() != expected {
+            bail!(
+                "block {} at offset {} has {} bytes instead of {expected}",
+                self.block,
+                self.offset,
+                data.len()
+            );
+        }
+
+        self.offset += (RECORD_HEADER_LEN + len) as u64;
+        Ok(Some(self.emit(chunk_type, len, data)))
+    }
+
+    // read the record at offset, the source being positioned there
+    fn read_record(&mut self, offset: u64) -> anyhow::Result<(ChunkType, Vec<u8>)> {
+        // read length and chunk type
+        let mut record = [0u8; RECORD_HEADER_LEN];
+        match self.src.read_exact(&mut record) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                bail!("image is truncated: end of chunk stream not found at offset {offset}")
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u64::from_be_bytes(record[0..8].try_into()?) as usize;
+        let chunk_type =
+            ChunkType::try_from(record[8]).map_err(|e| anyhow!("{e} at offset {offset}"))?;
+
+        // no block is bigger than block size, whatever compressed or not
+        let max_len = max_compressed_len(self.max_len());
+        if len > max_len {
+            bail!("corrupted record at offset {offset}: length {len} exceeds {max_len}");
+        }
+
+        // data to read for this record
+        let mut stored = vec![0u8; len];
+        self.src.read_exact(&mut stored).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                anyhow!("truncated record at offset {offset}: expected {len} bytes of data")
+            } else {
+                e.into()
+            }
+        })?;
+
+        Ok((chunk_type, stored))
+    }
+
+    // original data of a record holding data
+    fn decode(
+        &self,
+        chunk_type: ChunkType,
+        stored: Vec<u8>,
+        offset: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let algorithm = match chunk_type {
+            ChunkType::Zstd => Algorithm::Zstd,
+            ChunkType::Compressed => Algorithm::Lz4,
+            _ => return Ok(stored),
+        };
+
+        if self.header.dictionary && self.dictionary.is_none() {
+            bail!(
+                "dictionary needed to decompress block {} not loaded",
+                self.block
+            );
+        }
+
+        decompress(&stored, algorithm, self.max_len(), self.dictionary.as_ref()).map_err(|e| {
+            anyhow!(
+                "unable to decompress block {} at offset {offset}: {e}",
+                self.block
+            )
+        })
+    }
+
+    // original data of a chunk kept in the repository, checked against its key
+    fn fetch(&self, key: &ChunkKey) -> anyhow::Result<Vec<u8>> {
+        let repository = self.repository.as_ref().with_context(|| {
+            format!(
+                "repository holding block {} is not available, give it with --repo",
+                self.block
+            )
+        })?;
+
+        let (chunk_type, stored) = repository.get(key)?;
+        if !matches!(
+            chunk_type,
+            ChunkType::Raw | ChunkType::Compressed | ChunkType::Zstd
+        ) {
+            bail!("corrupted chunk {} in repository: {chunk_type:?}", hex(key));
+        }
+
+        let data = self.decode(chunk_type, stored, self.offset)?;
+        if blake3::hash(&data).as_bytes() != key {
+            bail!("corrupted chunk {} in repository: hash mismatch", hex(key));
+        }
+
+        Ok(data)
+    }
+
+    // next block of the parent image
+    fn parent_block(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0u8; self.expected_len()];
+        let parent = self.parent.as_mut().with_context(|| {
+            format!(
+                "parent image needed by block {} is not available, give it with --parent",
+                self.block
+            )
+        })?;
+
+        parent
+            .read_exact(&mut data)
+            .map_err(|e| anyhow!("unable to read block {} from parent image: {e}", self.block))?;
+        Ok(data)
+    }
+
+    // first block of the remaining zero bytes of a run, the rest being kept for next calls
+    fn zero_piece(&mut self, chunk_type: ChunkType, remaining: u64) -> Vec<u8> {
+        let len = remaining.min(self.header.block_size);
+        self.zero_run = (remaining > len).then_some((chunk_type, remaining - len));
+        vec![0u8; len as usize]
+    }
+
+    // maximum size of the data of a record once decoded
+    fn max_len(&self) -> usize {
+        let block_size = self.header.block_size as usize;
+        if self.header.cdc {
+            chunk_sizes(block_size).2
+        } else {
+            block_size
+        }
+    }
+
+    // last block might be shorter than block size
+    fn expected_len(&self) -> usize {
+        self.header
+            .block_size
+            .min(self.header.source_size.saturating_sub(self.logical_offset)) as usize
+    }
+
+    // give back a rebuilt block and move forward in the source
+    fn emit(&mut self, chunk_type: ChunkType, stored_len: usize, data: Vec<u8>) -> Block {
+        let block = Block {
+            number: self.block,
+            chunk_type,
+            stored_len,
+            data,
+        };
+
+        // pieces of a zero extent all belong to the same chunk
+        if chunk_type != ChunkType::ZeroExtent || self.zero_run.is_none() {
+            self.block += 1;
+        }
+        self.logical_offset += block.data.len() as u64;
+
+        block
+    }
+}
+
+impl<R: Read + Seek> ImageReader<BufReader<R>> {
+    // open an image, loading the dictionary stored in it if chunks were compressed with one,
+    // and the repository recorded in it if it's a manifest
+    pub fn open(mut src: R) -> anyhow::Result<Self> {
+        let header = ImageHeader::read(&mut src)?;
+
+        let dictionary = if header.dictionary {
+            let footer = Footer::read(&mut src)?;
+            let len = footer
+                .seek_section(&mut src, SectionKind::Dictionary)?
+                .context("image was compressed with a dictionary which is not in its footer")?;
+            Some(Dictionary::read(&mut src, len)?)
+        } else {
+            None
+        };
+
+        // a repository which moved can still be given later
+        let repository = if header.repository {
+            let footer = Footer::read(&mut src)?;
+            match footer.seek_section(&mut src, SectionKind::Repository)? {
+                Some(len) => {
+                    let mut path = vec![0u8; len as usize];
+                    src.read_exact(&mut path)?;
+                    Repository::open(&PathBuf::from(String::from_utf8(path)?)).ok()
+                }
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        src.seek(SeekFrom::Start(0))?;
+        let mut reader = Self::new(BufReader::new(src))?;
+        reader.dictionary = dictionary;
+        reader.repository = repository;
+        reader.recorded_parent = true;
+
+        Ok(reader)
+    }
+}
+
+// gives back the original byte stream
+impl<R: Read + Seek> Read for ImageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.next_block().map_err(io::Error::other)? {
+                Some(block) => {
+                    self.current = block.data;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{chunk::Chunk, writer::WriterParams};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    // build an image from blocks
+    fn image(blocks: &[Vec<u8>], compress: Option<Algorithm>) -> anyhow::Result<Vec<u8>> {
+        let params = WriterParams {
+            compress: compress.is_some(),
+            algorithm: compress.unwrap_or_default(),
+            block_size: BLOCK_SIZE,
+            source_size: blocks.iter().map(|b| b.len() as u64).sum(),
+            ..Default::default()
+        };
+
+        let mut image = Vec::new();
+        ImageHeader::from(&params).write(&mut image)?;
+        for block in blocks {
+            Chunk::try_from((block.as_slice(), &params))?.write(&mut image)?;
+        }
+        Chunk::end().write(&mut image)?;
+
+        Ok(image)
+    }
+
+    fn blocks() -> Vec<Vec<u8>> {
+        vec![
+            (0..BLOCK_SIZE).map(|i| i as u8).collect(),
+            vec![0u8; BLOCK_SIZE],
+            b"hello world".repeat(BLOCK_SIZE)[..BLOCK_SIZE].to_vec(),
+            vec![0u8; 100],
+        ]
+    }
+
+    #[test]
+    fn decode() -> anyhow::Result<()> {
+        let blocks = blocks();
+        let original = blocks.concat();
+
+        for compress in [None, Some(Algorithm::Lz4), Some(Algorithm::Zstd)] {
+            let image = image(&blocks, compress)?;
+
+            let mut decoded = Vec::new();
+            ImageReader::new(Cursor::new(&image))?.read_to_end(&mut decoded)?;
+            assert_eq!(decoded, original);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_types() -> anyhow::Result<()> {
+        let image = image(&blocks(), None)?;
+        let mut reader = ImageReader::new(Cursor::new(&image))?;
+
+        let mut types = Vec::new();
+        while let Some(block) = reader.next_block()? {
+            types.push(block.chunk_type);
+        }
+        assert_eq!(
+            types,
+            vec![
+                ChunkType::Raw,
+                ChunkType::FullOfZeros,
+                ChunkType::Raw,
+                ChunkType::FullOfZeros
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn zero_run() -> anyhow::Result<()> {
+        let params = WriterParams {
+            block_size: BLOCK_SIZE,
+            source_size: 5 * BLOCK_SIZE as u64 + 10,
+            ..Default::default()
+        };
+
+        // 5 zero blocks and a short one, followed by a raw block
+        let mut image = Vec::new();
+        ImageHeader::from(&params).write(&mut image)?;
+        Chunk::zero_run(5).write(&mut image)?;
+        Chunk::try_from((&[1u8; 10][..], &params))?.write(&mut image)?;
+        Chunk::end().write(&mut image)?;
+
+        let mut decoded = Vec::new();
+        ImageReader::new(Cursor::new(&image))?.read_to_end(&mut decoded)?;
+
+        let mut original = vec![0u8; 5 * BLOCK_SIZE];
+        original.extend_from_slice(&[1u8; 10]);
+        assert_eq!(decoded, original);
+
+        // a run going past the end of source
+        let mut image = Vec::new();
+        ImageHeader::from(&params).write(&mut image)?;
+        Chunk::zero_run(7).write(&mut image)?;
+        let err = ImageReader::new(Cursor::new(&image))?
+            .next_block()
+            .unwrap_err();
+        assert!(err.to_string().contains("corrupted zero run"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reference() -> anyhow::Result<()> {
+        let params = WriterParams {
+            block_size: BLOCK_SIZE,
+            source_size: 3 * BLOCK_SIZE as u64,
+            ..Default::default()
+        };
+        let block = b"hello world".repeat(BLOCK_SIZE)[..BLOCK_SIZE].to_vec();
+
+        // a block, a zero block, then the first one again
+        let mut image = Vec::new();
+        let first = ImageHeader::from(&params).write(&mut image)? as u64;
+        Chunk::raw(&block).write(&mut image)?;
+        Chunk::zeros().write(&mut image)?;
+        let reference = image.len();
+        Chunk::reference(first).write(&mut image)?;
+        Chunk::end().write(&mut image)?;
+
+        let mut decoded = Vec::new();
+        ImageReader::new(Cursor::new(&image))?.read_to_end(&mut decoded)?;
+        assert_eq!(
+            decoded,
+            [block.as_slice(), &[0u8; BLOCK_SIZE], &block].concat()
+        );
+
+        // a reference can't point to a zero chunk
+        let mut bad = image.clone();
+        let zeros = first + (RECORD_HEADER_LEN + BLOCK_SIZE) as u64;
+        bad[reference + RECORD_HEADER_LEN..reference + RECORD_HEADER_LEN + 8]
+            .copy_from_slice(&zeros.to_be_bytes());
+        let err = ImageReader::new(Cursor::new(&bad))?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("points to a FullOfZeros chunk"));
+
+        // nor forward
+        bad[reference + RECORD_HEADER_LEN..reference + RECORD_HEADER_LEN + 8]
+            .copy_from_slice(&(reference as u64).to_be_bytes());
+        let err = ImageReader::new(Cursor::new(&bad))?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("points forward"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() -> anyhow::Result<()> {
+        let mut image = image(&blocks(), None)?;
+
+        // without end marker
+        image.truncate(image.len() - RECORD_HEADER_LEN);
+        let err = ImageReader::new(Cursor::new(&image))?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("end of chunk stream not found"));
+
+        // in the middle of a record
+        image.truncate(image.len() - RECORD_HEADER_LEN - 10);
+        let err = ImageReader::new(Cursor::new(&image))?
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("truncated record"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted() -> anyhow::Result<()> {
+        let blocks = vec![b"hello world".repeat(100)];
+        let image = image(&blocks, Some(Algorithm::Lz4))?;
+        let start = ImageHeader::read(&mut image.as_slice())?.encoded_len();
+
+        // unknown chunk type
+        let mut bad = image.clone();
+        bad[start + 8] = 42;
+        let err = ImageReader::new(Cursor::new(&bad))?
+            .next_block()
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown chunk type 42"));
+
+        // bad LZ4 data
+        let mut bad = image.clone();
+        let data = start + RECORD_HEADER_LEN;
+        bad[data..data + 4].copy_from_slice(&[0xFF; 4]);
+        let err = ImageReader::new(Cursor::new(&bad))?
+            .next_block()
+            .unwrap_err();
+        assert!(err.to_string().contains("unable to decompress"));
+
+        Ok(())
+    }
+}
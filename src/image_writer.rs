@@ -0,0 +1,357 @@
+// image writer: serializes chunks into the output file, along with header, index and footer
+// for dimg images, or as raw data in dd mode, optionally through a stream compressor
+
+use std::{collections::HashMap, io::Write, path::PathBuf, sync::Arc};
+
+use log::{debug, info, warn};
+
+// memory taken by each block hash kept for deduplication, hash table overhead included
+pub const DEDUP_ENTRY_LEN: u64 = 40;
+
+use crate::{
+    cbt::BlockHashes,
+    chunk::{Chunk, ChunkType, RECORD_HEADER_LEN},
+    compression::Dictionary,
+    footer::{Footer, SectionKind},
+    header::ImageHeader,
+    index::{IndexEntry, IndexSpool},
+    merkle::MerkleTree,
+    metadata::Metadata,
+    repository::{Acquisition, Repository},
+    segment::SegmentWriter,
+    stream::{Output, StreamEncoder},
+    trailer::Trailer,
+    window::HashWindows,
+    writer::WriterParams,
+};
+
+pub struct ImageWriter {
+    writer: Output,
+
+    // output file as given
+    path: PathBuf,
+
+    // true if output is raw data only
+    dd: bool,
+
+    // current offset in the output file
+    offset: u64,
+
+    // offset in the source of the next block
+    start: u64,
+
+    // true if blocks are content-defined chunks of variable size
+    cdc: bool,
+
+    // where each chunk lands, none in dd mode
+    index: Option<IndexSpool>,
+
+    // run of zero or parent blocks not written yet:
+    // (chunk type, first block, number of blocks, number of bytes)
+    run: Option<(ChunkType, u64, u64, u64)>,
+
+    // case metadata written after the chunk stream
+    metadata: Metadata,
+
+    // dictionary chunks were compressed with
+    dictionary: Option<Arc<Dictionary>>,
+
+    // when deduplicating: offset of the first record written for each block hash. It grows
+    // with each distinct block, up to dedup_entries
+    dedup: Option<HashMap<u128, u64>>,
+    dedup_entries: Option<usize>,
+    dedup_full: bool,
+
+    // true if records are compared before being referenced
+    dedup_verify: bool,
+
+    // repository data chunks are stored in, with the number and size of chunks added to it
+    repository: Option<Repository>,
+    new_chunks: u64,
+    new_bytes: u64,
+}
+
+impl ImageWriter {
+    // create output file and write header, unless in dd mode
+    pub fn create(params: &WriterParams) -> anyhow::Result<Self> {
+        let path = params
+            .output_file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no output file"))?;
+
+        // compressing in dd mode gives a standard compressed stream
+        let writer = if params.changed_only {
+            SegmentWriter::update(path)?
+        } else {
+            SegmentWriter::create(path, params.segment_size)?
+        };
+        let writer = if params.dd && params.compress {
+            Output::Stream(StreamEncoder::new(writer, params.algorithm, params.level)?)
+        } else {
+            Output::Plain(writer)
+        };
+
+        let mut image = Self {
+            writer,
+            path: path.clone(),
+            dd: params.dd,
+            offset: 0,
+            start: 0,
+            cdc: params.cdc,
+            index: (!params.dd).then(|| IndexSpool::create(path)).transpose()?,
+            run: None,
+            metadata: params.metadata.clone(),
+            dictionary: params.dictionary.clone(),
+            dedup: params.dedup.then(HashMap::new),
+            dedup_entries: params.dedup_entries,
+            dedup_full: false,
+            dedup_verify: params.dedup_verify,
+            repository: params
+                .repository
+                .as_deref()
+                .map(Repository::create)
+                .transpose()?,
+            new_chunks: 0,
+            new_bytes: 0,
+        };
+
+        if params.dd && !params.metadata.is_empty() {
+            warn!("metadata can't be stored in dd mode output, ignoring it");
+        }
+
+        // dd-like output is raw data only, otherwise the image starts with a header
+        if !params.dd {
+            let header = ImageHeader::from(params);
+            debug!("header: {:?}", header);
+            image.offset += header.write(&mut image.writer)? as u64;
+        }
+
+        Ok(image)
+    }
+
+    // write the chunk built from block number of size bytes, consecutive zero blocks or blocks
+    // taken from the parent being merged into runs. Returns how the block was stored
+    pub fn write_chunk(
+        &mut self,
+        block: u64,
+        size: usize,
+        chunk: &Chunk,
+    ) -> anyhow::Result<ChunkType> {
+        if matches!(chunk.chunk_type, ChunkType::FullOfZeros | ChunkType::Parent) {
+            match self.run {
+                Some((chunk_type, _, ref mut count, ref mut bytes))
+                    if chunk_type == chunk.chunk_type =>
+                {
+                    *count += 1;
+                    *bytes += size as u64;
+                }
+                _ => {
+                    self.flush_run()?;
+                    self.run = Some((chunk.chunk_type, block, 1, size as u64));
+                }
+            }
+            self.start += size as u64;
+            return Ok(chunk.chunk_type);
+        }
+
+        self.flush_run()?;
+        let chunk_type = self.write_data(block, chunk)?;
+        self.start += size as u64;
+
+        Ok(chunk_type)
+    }
+
+    // write a chunk holding data, or a reference if the same data was already written
+    fn write_data(&mut self, block: u64, chunk: &Chunk) -> anyhow::Result<ChunkType> {
+        let start = self.start;
+
+        // data goes to the repository, only its key is kept in the manifest
+        if let (Some(repository), Some(key)) = (&self.repository, &chunk.key) {
+            if let Some(bytes) = repository.put(key, chunk)? {
+                self.new_chunks += 1;
+                self.new_bytes += bytes;
+            }
+            self.write_record(block, start, &Chunk::in_repository(key))?;
+            return Ok(ChunkType::Repository);
+        }
+
+        // a block already written is replaced by a reference to its record
+        if let Some(hash) = chunk.hash
+            && let Some(dedup) = &mut self.dedup
+        {
+            match dedup.get(&hash).copied() {
+                Some(offset) => {
+                    if !self.dedup_verify || self.same_record(offset, chunk)? {
+                        self.write_record(block, start, &Chunk::reference(offset))?;
+                        return Ok(ChunkType::Reference);
+                    }
+                    warn!(
+                        "block {block} has the hash of record at offset {offset} but not its data"
+                    );
+                }
+                // once full, blocks not seen yet are written as is
+                None if self.dedup_entries.is_some_and(|max| dedup.len() >= max) => {
+                    if !self.dedup_full {
+                        warn!("deduplication table is full, new blocks are not deduplicated");
+                        self.dedup_full = true;
+                    }
+                }
+                None => {
+                    dedup.insert(hash, self.offset);
+                }
+            }
+        }
+
+        self.write_record(block, start, chunk)?;
+        Ok(chunk.chunk_type)
+    }
+
+    // close the chunk stream and add index, trailer and footer for random access
+    pub fn finish(
+        mut self,
+        trailer: &Trailer,
+        windows: Option<&HashWindows>,
+        merkle: Option<&MerkleTree>,
+        block_hashes: Option<&BlockHashes>,
+    ) -> anyhow::Result<()> {
+        if !self.dd {
+            self.flush_run()?;
+            self.offset += Chunk::end().write(&mut self.writer)? as u64;
+
+            let mut footer = Footer::default();
+            let index_len = match &mut self.index {
+                Some(index) => index.copy_to(&mut self.writer)?,
+                None => 0,
+            };
+            footer.push(SectionKind::Index, self.offset, index_len);
+            self.offset += index_len;
+
+            if let Some(dictionary) = &self.dictionary {
+                let dictionary_len = dictionary.write(&mut self.writer)? as u64;
+                footer.push(SectionKind::Dictionary, self.offset, dictionary_len);
+                self.offset += dictionary_len;
+            }
+
+            if let Some(repository) = &self.repository {
+                let path = repository.path().to_string_lossy();
+                self.writer.write_all(path.as_bytes())?;
+                footer.push(SectionKind::Repository, self.offset, path.len() as u64);
+                self.offset += path.len() as u64;
+            }
+
+            if let Some(windows) = windows {
+                let windows_len = windows.write(&mut self.writer)? as u64;
+                footer.push(SectionKind::HashWindows, self.offset, windows_len);
+                self.offset += windows_len;
+            }
+
+            if let Some(merkle) = merkle {
+                let merkle_len = merkle.write(&mut self.writer)? as u64;
+                footer.push(SectionKind::Merkle, self.offset, merkle_len);
+                self.offset += merkle_len;
+            }
+
+            if let Some(block_hashes) = block_hashes {
+                let hashes_len = block_hashes.write_section(&mut self.writer)? as u64;
+                footer.push(SectionKind::BlockHashes, self.offset, hashes_len);
+                self.offset += hashes_len;
+            }
+
+            if !self.metadata.is_empty() {
+                let metadata_len = self.metadata.write(&mut self.writer)? as u64;
+                footer.push(SectionKind::Metadata, self.offset, metadata_len);
+                self.offset += metadata_len;
+            }
+
+            let trailer_len = trailer.write(&mut self.writer)? as u64;
+            footer.push(SectionKind::Trailer, self.offset, trailer_len);
+
+            footer.write(&mut self.writer)?;
+        }
+
+        self.writer.finish()?;
+
+        if let Some(repository) = &self.repository {
+            info!(
+                "repository: {} new chunks, {} bytes",
+                self.new_chunks, self.new_bytes
+            );
+            repository.record(&Acquisition {
+                manifest: self
+                    .path
+                    .canonicalize()
+                    .unwrap_or(self.path)
+                    .display()
+                    .to_string(),
+                source_size: trailer.bytes_read,
+                new_chunks: self.new_chunks,
+                new_bytes: self.new_bytes,
+                end_time: trailer.end_time,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // true if the record at offset is the one chunk would be written as
+    fn same_record(&mut self, offset: u64, chunk: &Chunk) -> anyhow::Result<bool> {
+        let mut expected = Vec::with_capacity(chunk.encoded_len());
+        chunk.write(&mut expected)?;
+
+        // length and type first, record might be shorter than expected
+        let mut record = vec![0u8; RECORD_HEADER_LEN];
+        self.writer.read_at(&mut record, offset)?;
+        if record != expected[..RECORD_HEADER_LEN] {
+            return Ok(false);
+        }
+
+        record.resize(expected.len() - RECORD_HEADER_LEN, 0);
+        self.writer
+            .read_at(&mut record, offset + RECORD_HEADER_LEN as u64)?;
+        Ok(record == expected[RECORD_HEADER_LEN..])
+    }
+
+    // a single zero block is kept as is, more are written as a run. Content-defined chunks
+    // have no fixed size, so their length is kept instead
+    fn flush_run(&mut self) -> anyhow::Result<()> {
+        let Some((chunk_type, block, count, bytes)) = self.run.take() else {
+            return Ok(());
+        };
+        let start = self.start - bytes;
+
+        match count {
+            _ if chunk_type == ChunkType::Parent => {
+                debug!("run of {count} parent blocks from block {block}");
+                self.write_record(block, start, &Chunk::parent_run(count))
+            }
+            _ if self.cdc => self.write_record(block, start, &Chunk::zero_extent(bytes)),
+            1 => self.write_record(block, start, &Chunk::zeros()),
+            _ => {
+                debug!("run of {count} zero blocks from block {block}");
+                self.write_record(block, start, &Chunk::zero_run(count))
+            }
+        }
+    }
+
+    fn write_record(&mut self, block: u64, start: u64, chunk: &Chunk) -> anyhow::Result<()> {
+        if let Some(index) = &mut self.index {
+            index.push(&IndexEntry {
+                block,
+                start,
+                offset: self.offset,
+                chunk_type: chunk.chunk_type,
+                len: chunk.len as u64,
+            })?;
+        }
+
+        if chunk.chunk_type == ChunkType::Hole {
+            self.writer.skip(chunk.len as u64)?;
+            self.offset += chunk.len as u64;
+        } else {
+            self.writer.start_record(chunk.encoded_len())?;
+            self.offset += chunk.write(&mut self.writer)? as u64;
+        }
+
+        Ok(())
+    }
+}
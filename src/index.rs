@@ -0,0 +1,207 @@
+// chunk index written at the end of the image
+//
+// each entry maps a block number, or a chunk number with content-defined chunking, and the
+// offset of its data in the source to the position of its record in the image, so any range
+// of the source can be located without decoding the chunk stream. Layout (big-endian):
+//
+// block (8) | source offset (8) | file offset (8) | chunk type (1) | stored length (8)
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+
+use crate::chunk::ChunkType;
+
+// length of an index entry once written
+pub const ENTRY_LEN: usize = 8 + 8 + 8 + 1 + 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IndexEntry {
+    // block number in the source
+    pub block: u64,
+
+    // offset in the source of the first byte of the block, chunks being of variable size
+    pub start: u64,
+
+    // offset of the chunk record from the beginning of the image
+    pub offset: u64,
+
+    // chunk type of the record
+    pub chunk_type: ChunkType,
+
+    // length of data stored in the record, without length and chunk type
+    pub len: u64,
+}
+
+impl IndexEntry {
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<()> {
+        dst.write_all(&self.block.to_be_bytes())?;
+        dst.write_all(&self.start.to_be_bytes())?;
+        dst.write_all(&self.offset.to_be_bytes())?;
+        dst.write_all(&[self.chunk_type as u8])?;
+        dst.write_all(&self.len.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+// entries of an image being written. They're kept in a temporary file next to the image
+// rather than in memory, which would grow with the source, until copied to the index section
+pub struct IndexSpool {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl IndexSpool {
+    pub fn create(image: &Path) -> anyhow::Result<Self> {
+        let mut name = image.as_os_str().to_owned();
+        name.push(".index.tmp");
+        let path = PathBuf::from(name);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("unable to create index file {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn push(&mut self, entry: &IndexEntry) -> anyhow::Result<()> {
+        entry.write(&mut self.file)
+    }
+
+    // write entries pushed so far, returning the number of bytes written
+    pub fn copy_to<W: Write>(&mut self, dst: &mut W) -> anyhow::Result<u64> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        Ok(io::copy(file, dst)?)
+    }
+}
+
+// the temporary file goes away whether the image was finished or not
+impl Drop for IndexSpool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChunkIndex {
+    // entries are sorted by block number because chunks are written in order
+    entries: Vec<IndexEntry>,
+}
+
+impl ChunkIndex {
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    // find the entry holding the byte at this offset of the source, up to the next entry
+    pub fn locate(&self, source_offset: u64) -> Option<&IndexEntry> {
+        let pos = self.entries.partition_point(|e| e.start <= source_offset);
+        pos.checked_sub(1).and_then(|i| self.entries.get(i))
+    }
+
+    // read an index section of len bytes
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        if !len.is_multiple_of(ENTRY_LEN as u64) {
+            bail!("corrupted index: length {len} is not a multiple of {ENTRY_LEN}");
+        }
+
+        let count = len as usize / ENTRY_LEN;
+        let mut entries = Vec::with_capacity(count);
+        let mut buf = [0u8; ENTRY_LEN];
+
+        for _ in 0..count {
+            src.read_exact(&mut buf)?;
+            entries.push(IndexEntry {
+                block: u64::from_be_bytes(buf[0..8].try_into()?),
+                start: u64::from_be_bytes(buf[8..16].try_into()?),
+                offset: u64::from_be_bytes(buf[16..24].try_into()?),
+                chunk_type: ChunkType::try_from(buf[24])?,
+                len: u64::from_be_bytes(buf[25..33].try_into()?),
+            });
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn index() -> ChunkIndex {
+        let mut entries = Vec::new();
+        for block in 0..10 {
+            entries.push(IndexEntry {
+                block,
+                start: block * 32768,
+                offset: 36 + block * 100,
+                chunk_type: ChunkType::Raw,
+                len: 91,
+            });
+        }
+
+        // blocks 10 to 19 are zeros
+        entries.push(IndexEntry {
+            block: 10,
+            start: 10 * 32768,
+            offset: 1036,
+            chunk_type: ChunkType::ZeroRun,
+            len: 8,
+        });
+        entries.push(IndexEntry {
+            block: 20,
+            start: 20 * 32768,
+            offset: 1053,
+            chunk_type: ChunkType::Raw,
+            len: 91,
+        });
+        ChunkIndex { entries }
+    }
+
+    #[test]
+    fn locate() {
+        let index = index();
+
+        assert_eq!(index.locate(12).unwrap().offset, 36);
+        assert_eq!(index.locate(32768 * 20).unwrap().offset, 1053);
+        assert_eq!(index.locate(32768 * 3 + 12).unwrap().block, 3);
+        assert_eq!(index.locate(32768 * 15).unwrap().block, 10);
+    }
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let dir = TempDir::new("index");
+        let index = index();
+
+        // entries go through the temporary file, which is gone once the spool is dropped
+        let mut spool = IndexSpool::create(&dir.join("image.img"))?;
+        for entry in index.entries() {
+            spool.push(entry)?;
+        }
+        let mut buf = Vec::new();
+        let n = spool.copy_to(&mut buf)?;
+        assert_eq!(n, 12 * ENTRY_LEN as u64);
+        assert!(dir.join("image.img.index.tmp").exists());
+        drop(spool);
+        assert!(!dir.join("image.img.index.tmp").exists());
+
+        let read = ChunkIndex::read(&mut buf.as_slice(), n)?;
+        assert_eq!(read.entries(), index.entries());
+
+        assert!(ChunkIndex::read(&mut buf.as_slice(), n - 1).is_err());
+        Ok(())
+    }
+}
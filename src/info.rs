@@ -0,0 +1,519 @@
+// info module: summarize the content of an existing image
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use anyhow::Context;
+use human_bytes::human_bytes;
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{
+    args::Args,
+    cbt::BlockHashes,
+    chunk::ChunkType,
+    footer::{Footer, SectionKind},
+    hash::hex,
+    header::ImageHeader,
+    image_reader::ImageReader,
+    index::ChunkIndex,
+    merkle::MerkleTree,
+    metadata::Metadata,
+    repository::Repository,
+    segment::SegmentReader,
+    trailer::{Trailer, rfc3339},
+    window::HashWindows,
+};
+
+// what we know about an image
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    // image file name
+    pub image: String,
+
+    // header as read from image
+    pub header: ImageHeader,
+
+    // number of chunks per chunk type
+    pub chunks: BTreeMap<String, u64>,
+
+    // number of blocks of the source
+    pub blocks: u64,
+
+    // size of the source rebuilt from image
+    pub logical_size: u64,
+
+    // size of the image file
+    pub stored_size: u64,
+
+    // logical size / stored size
+    pub compression_ratio: f64,
+
+    // fraction of blocks full of zeros
+    pub zero_blocks: f64,
+
+    // true if stats were gathered from the index, false if the chunk stream was decoded
+    pub indexed: bool,
+
+    // case metadata
+    pub metadata: Metadata,
+
+    // acquisition outcome, missing in images written by older versions
+    pub trailer: Option<Trailer>,
+
+    // digests of every window of the source, if asked for
+    pub hash_windows: Option<HashWindows>,
+
+    // Merkle tree over blocks, its root being the fingerprint of the image
+    pub merkle: Option<MerkleTree>,
+}
+
+impl ImageInfo {
+    // gather info from the index if any, or by decoding the whole image
+    pub fn from_image(file: &mut SegmentReader, name: &str) -> anyhow::Result<Self> {
+        let stored_size = file.size();
+        let header = ImageHeader::read(file)?;
+        debug!("header: {:?}", header);
+
+        let mut chunks = BTreeMap::<String, u64>::new();
+        let mut blocks = 0u64;
+        let mut zeros = 0u64;
+        let mut logical_size = 0u64;
+
+        // tells how much of the source was read, which can be less than its size
+        let trailer = or_warn("trailer", read_trailer(file));
+
+        let indexed = match read_index(file) {
+            Ok(index) => {
+                let entries = index.entries();
+                let read = trailer
+                    .as_ref()
+                    .map_or(header.source_size, |t| t.bytes_read);
+                let total_blocks = read.div_ceil(header.block_size.max(1));
+
+                for (i, entry) in entries.iter().enumerate() {
+                    *chunks.entry(format!("{:?}", entry.chunk_type)).or_default() += 1;
+
+                    // a zero or parent run spans up to the next entry
+                    let next = entries.get(i + 1).map_or(total_blocks, |e| e.block);
+                    let count = match entry.chunk_type {
+                        ChunkType::ZeroRun | ChunkType::Parent => next.saturating_sub(entry.block),
+                        _ => 1,
+                    };
+
+                    blocks += count;
+                    if matches!(
+                        entry.chunk_type,
+                        ChunkType::FullOfZeros | ChunkType::ZeroRun | ChunkType::ZeroExtent
+                    ) {
+                        zeros += count;
+                    }
+                }
+
+                // without a trailer, only the indexed blocks tell what was read; chunk
+                // lengths are not indexed, but chunked images always have a trailer
+                logical_size = match &trailer {
+                    Some(t) => t.bytes_read,
+                    None if header.cdc => header.source_size,
+                    None => (blocks * header.block_size).min(header.source_size),
+                };
+                true
+            }
+            Err(e) => {
+                warn!("no usable index ({e}), decoding whole image");
+
+                file.seek(SeekFrom::Start(0))?;
+                let mut decoder = ImageReader::open(&mut *file)?;
+
+                while let Some(block) = decoder.next_block()? {
+                    // only the first block of a run comes from a record
+                    let run = matches!(
+                        block.chunk_type,
+                        ChunkType::ZeroRun | ChunkType::ZeroExtent | ChunkType::Parent
+                    );
+                    if !run || block.stored_len > 0 {
+                        *chunks.entry(format!("{:?}", block.chunk_type)).or_default() += 1;
+                    }
+                    if matches!(
+                        block.chunk_type,
+                        ChunkType::FullOfZeros | ChunkType::ZeroRun | ChunkType::ZeroExtent
+                    ) {
+                        zeros += 1;
+                    }
+
+                    blocks += 1;
+                    logical_size += block.data.len() as u64;
+                }
+                false
+            }
+        };
+
+        Ok(Self {
+            image: name.to_string(),
+            header,
+            chunks,
+            blocks,
+            logical_size,
+            stored_size,
+            compression_ratio: ratio(logical_size, stored_size),
+            zero_blocks: ratio(zeros, blocks),
+            indexed,
+            metadata: or_warn("metadata", read_metadata(file)),
+            trailer,
+            hash_windows: or_warn("hash windows", read_hash_windows(file)),
+            merkle: or_warn("Merkle tree", read_merkle(file)),
+        })
+    }
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.header;
+
+        writeln!(f, "{:<20}{}", "image:", self.image)?;
+        writeln!(f, "{:<20}{}", "format version:", h.version)?;
+        writeln!(f, "{:<20}{}", "dimg version:", h.dimg_version)?;
+        writeln!(f, "{:<20}{}", "block size:", h.block_size)?;
+        writeln!(
+            f,
+            "{:<20}{} ({})",
+            "source size:",
+            h.source_size,
+            human_bytes(h.source_size as f64)
+        )?;
+        match (h.compress, h.level) {
+            (false, _) => writeln!(f, "{:<20}false", "compress:")?,
+            (true, 0) => writeln!(f, "{:<20}{}", "compress:", h.algorithm)?,
+            (true, level) => writeln!(f, "{:<20}{}, level {level}", "compress:", h.algorithm)?,
+        }
+        if h.dictionary {
+            writeln!(f, "{:<20}{}", "dictionary:", h.dictionary)?;
+        }
+        if h.cdc {
+            writeln!(f, "{:<20}content-defined", "chunking:")?;
+        }
+        if let Some(parent) = &h.parent {
+            writeln!(f, "{:<20}{} ({})", "parent:", parent.path, parent.digest)?;
+        }
+        let hashes: Vec<_> = h.hashes.iter().map(|a| a.name()).collect();
+        writeln!(f, "{:<20}{}", "hashes:", hashes.join(", "))?;
+
+        writeln!(f, "{:<20}{}", "blocks:", self.blocks)?;
+        writeln!(f, "{:<20}{}", "chunks:", self.chunks.values().sum::<u64>())?;
+        for (chunk_type, count) in &self.chunks {
+            writeln!(f, "  {:<18}{count}", format!("{chunk_type}:"))?;
+        }
+
+        writeln!(
+            f,
+            "{:<20}{} ({})",
+            "logical size:",
+            self.logical_size,
+            human_bytes(self.logical_size as f64)
+        )?;
+        writeln!(
+            f,
+            "{:<20}{} ({})",
+            "stored size:",
+            self.stored_size,
+            human_bytes(self.stored_size as f64)
+        )?;
+        writeln!(
+            f,
+            "{:<20}{:.2}",
+            "compression ratio:", self.compression_ratio
+        )?;
+        write!(f, "{:<20}{:.2}%", "zero blocks:", self.zero_blocks * 100.0)?;
+
+        if let Some(m) = &self.merkle {
+            write!(f, "\n{:<20}merkle:{}", "fingerprint:", hex(&m.root))?;
+        }
+        if let Some(w) = &self.hash_windows {
+            write!(
+                f,
+                "\n{:<20}{} of {} ({})",
+                "hash windows:",
+                w.windows.len(),
+                w.size,
+                w.algorithms.join(", ")
+            )?;
+        }
+
+        if !self.metadata.is_empty() {
+            write!(f, "\nmetadata:")?;
+            for (key, value) in self.metadata.iter() {
+                write!(f, "\n  {:<18}{value}", format!("{key}:"))?;
+            }
+        }
+
+        if let Some(t) = &self.trailer {
+            write!(f, "\ntrailer:")?;
+            write!(f, "\n  {:<18}{}", "completed:", t.completed)?;
+            write!(f, "\n  {:<18}{}", "bytes read:", t.bytes_read)?;
+            write!(f, "\n  {:<18}{}", "start time:", rfc3339(t.start_time))?;
+            write!(f, "\n  {:<18}{}", "end time:", rfc3339(t.end_time))?;
+            write!(f, "\n  {:<18}{}", "errors:", t.errors)?;
+            for (algorithm, digest) in &t.digests {
+                write!(f, "\n  {:<18}{digest}", format!("{algorithm}:"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// print info on image given by --if, or stats of a repository
+pub fn info(args: &Args) -> anyhow::Result<()> {
+    if args.r#if.is_dir() {
+        let stats = Repository::open(&args.r#if)?.stats()?;
+
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            println!("{stats}");
+        }
+        return Ok(());
+    }
+
+    let mut file = SegmentReader::open(&args.r#if)?;
+    let info = ImageInfo::from_image(&mut file, &args.r#if.display().to_string())?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("{info}");
+    }
+
+    Ok(())
+}
+
+// locate index using the footer and load it
+pub fn read_index(file: &mut SegmentReader) -> anyhow::Result<ChunkIndex> {
+    let footer = Footer::read(file)?;
+    let len = footer
+        .seek_section(file, SectionKind::Index)?
+        .context("no index section in footer")?;
+
+    ChunkIndex::read(&mut BufReader::new(file), len)
+}
+
+// load metadata section if any
+fn read_metadata(file: &mut SegmentReader) -> anyhow::Result<Metadata> {
+    let footer = Footer::read(file)?;
+
+    match footer.seek_section(file, SectionKind::Metadata)? {
+        Some(len) => Metadata::read(&mut BufReader::new(file), len),
+        None => Ok(Metadata::default()),
+    }
+}
+
+// load acquisition trailer if any
+pub fn read_trailer(file: &mut SegmentReader) -> anyhow::Result<Option<Trailer>> {
+    let footer = Footer::read(file)?;
+
+    match footer.seek_section(file, SectionKind::Trailer)? {
+        Some(len) => Ok(Some(Trailer::read(file, len)?)),
+        None => Ok(None),
+    }
+}
+
+// load digests of windows if any
+pub fn read_hash_windows(file: &mut SegmentReader) -> anyhow::Result<Option<HashWindows>> {
+    let footer = Footer::read(file)?;
+
+    match footer.seek_section(file, SectionKind::HashWindows)? {
+        Some(len) => Ok(Some(HashWindows::read(&mut BufReader::new(file), len)?)),
+        None => Ok(None),
+    }
+}
+
+// load the Merkle tree if any
+pub fn read_merkle(file: &mut SegmentReader) -> anyhow::Result<Option<MerkleTree>> {
+    let footer = Footer::read(file)?;
+
+    match footer.seek_section(file, SectionKind::Merkle)? {
+        Some(len) => Ok(Some(MerkleTree::read(&mut BufReader::new(file), len)?)),
+        None => Ok(None),
+    }
+}
+
+// load the digests of every block if any
+pub fn read_block_hashes(file: &mut SegmentReader) -> anyhow::Result<Option<BlockHashes>> {
+    let footer = Footer::read(file)?;
+
+    match footer.seek_section(file, SectionKind::BlockHashes)? {
+        Some(len) => Ok(Some(BlockHashes::read_section(
+            &mut BufReader::new(file).take(len),
+        )?)),
+        None => Ok(None),
+    }
+}
+
+// a damaged section is reported, not silently taken as missing
+fn or_warn<T: Default>(section: &str, res: anyhow::Result<T>) -> T {
+    res.unwrap_or_else(|e| {
+        warn!("unable to read {section}: {e}");
+        T::default()
+    })
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 { 0.0 } else { a as f64 / b as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::Algorithm,
+        hash::{HashAlgorithm, Hashes},
+        header::FORMAT_VERSION,
+        test_util::{TempDir, random_bytes, write_image},
+        writer::WriterParams,
+    };
+
+    // compressible, zero and incompressible blocks, with windows, a Merkle tree and metadata.
+    // Gives back the image info and the sha256 of the source
+    fn image(dir: &TempDir) -> anyhow::Result<(ImageInfo, String)> {
+        let path = dir.join("image.img");
+
+        let mut data = b"hello world".repeat(16 * 4096 / 11 + 1);
+        data.truncate(16 * 4096);
+        data[4 * 4096..10 * 4096].fill(0);
+        data[10 * 4096..15 * 4096].copy_from_slice(&random_bytes(5 * 4096));
+        data[15 * 4096..].fill(0);
+
+        let mut metadata = Metadata::default();
+        metadata.set("case", "42");
+        let params = WriterParams {
+            output_file: Some(path.clone()),
+            block_size: 4096,
+            source_size: data.len() as u64,
+            compress: true,
+            algorithm: Algorithm::Zstd,
+            hashes: vec![HashAlgorithm::Sha256],
+            hash_window: Some(8 * 4096),
+            merkle: true,
+            metadata,
+            nb_workers: 4,
+            ..Default::default()
+        };
+        write_image(params, data.chunks(4096))?;
+
+        let mut hashes = Hashes::new(&[HashAlgorithm::Sha256]);
+        hashes.update(&data);
+        let info = ImageInfo::from_image(&mut SegmentReader::open(&path)?, "image.img")?;
+        Ok((info, hashes.digests()[0].1.clone()))
+    }
+
+    #[test]
+    fn text() -> anyhow::Result<()> {
+        let dir = TempDir::new("info-text");
+        let (info, sha256) = image(&dir)?;
+        let root = hex(&info.merkle.as_ref().unwrap().root);
+
+        // sizes of compressed data and times change from one run to the other
+        let text = info.to_string();
+        let volatile = [
+            "stored size:",
+            "compression ratio:",
+            "start time:",
+            "end time:",
+        ];
+        let lines: Vec<_> = text
+            .lines()
+            .filter(|l| !volatile.iter().any(|v| l.trim_start().starts_with(v)))
+            .collect();
+
+        let expected = format!(
+            "\
+image:              image.img
+format version:     {FORMAT_VERSION}
+dimg version:       {}
+block size:         4096
+source size:        65536 (64 KiB)
+compress:           zstd
+hashes:             sha256
+blocks:             16
+chunks:             11
+  FullOfZeros:      1
+  Raw:              5
+  ZeroRun:          1
+  Zstd:             4
+logical size:       65536 (64 KiB)
+zero blocks:        43.75%
+fingerprint:        merkle:{root}
+hash windows:       2 of 32768 (sha256)
+metadata:
+  case:             42
+trailer:
+  completed:        true
+  bytes read:       65536
+  errors:           0
+  sha256:           {sha256}",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> anyhow::Result<()> {
+        let dir = TempDir::new("info-json");
+        let (info, sha256) = image(&dir)?;
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string_pretty(&info)?)?;
+
+        assert_eq!(json["image"], "image.img");
+        assert_eq!(json["header"]["version"], FORMAT_VERSION);
+        assert_eq!(json["header"]["block_size"], 4096);
+        assert_eq!(json["header"]["algorithm"], "zstd");
+        assert_eq!(json["header"]["hashes"], serde_json::json!(["sha256"]));
+        assert_eq!(
+            json["chunks"],
+            serde_json::json!({"FullOfZeros": 1, "Raw": 5, "ZeroRun": 1, "Zstd": 4})
+        );
+        assert_eq!(json["blocks"], 16);
+        assert_eq!(json["logical_size"], 65536);
+        assert_eq!(json["zero_blocks"], 0.4375);
+        assert_eq!(json["indexed"], true);
+        assert_eq!(json["metadata"], serde_json::json!({"case": "42"}));
+
+        // sections of the footer
+        assert_eq!(json["trailer"]["completed"], true);
+        assert_eq!(json["trailer"]["bytes_read"], 65536);
+        assert_eq!(json["trailer"]["digests"]["sha256"], sha256);
+        assert_eq!(json["hash_windows"]["size"], 32768);
+        assert_eq!(json["hash_windows"]["windows"].as_array().unwrap().len(), 2);
+        assert_eq!(json["merkle"]["leaves"], 16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_read() -> anyhow::Result<()> {
+        let dir = TempDir::new("info");
+        let path = dir.join("image.img");
+
+        // the source ends before its announced size
+        let data = random_bytes(10 * 4096);
+        let params = WriterParams {
+            output_file: Some(path.clone()),
+            block_size: 4096,
+            source_size: 16 * 4096,
+            nb_workers: 4,
+            ..Default::default()
+        };
+        write_image(params, data.chunks(4096))?;
+
+        let info = ImageInfo::from_image(&mut SegmentReader::open(&path)?, "image.img")?;
+        assert!(info.indexed);
+        assert_eq!(info.blocks, 10);
+        assert_eq!(info.logical_size, data.len() as u64);
+
+        Ok(())
+    }
+}
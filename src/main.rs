@@ -1,11 +1,16 @@
 mod args;
-use std::collections::HashMap;
+use std::path::Path;
 use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, mpsc};
 use std::thread;
 use std::time::Instant;
 
 use crate::args::get_args;
+use crate::cbt::{BlockHash, BlockHashes};
+use crate::compression::Dictionary;
+use crate::hash::{print_digests, write_sum_files};
+use crate::metadata::Metadata;
+use crate::parent::Parent;
 use crate::reader::{RunContext, read_par};
 use crate::writer::{WriterParams, writer_thread};
 
@@ -13,11 +18,33 @@ mod device;
 use anyhow::Ok;
 use device::Device;
 
+mod cbt;
+mod cdc;
 mod chunk;
+mod compression;
+mod footer;
 mod hash;
+mod header;
+mod image_reader;
+mod image_writer;
+mod index;
+mod info;
+mod merkle;
+mod metadata;
+mod parent;
 mod reader;
+mod repository;
+mod restore;
+mod segment;
+mod stream;
+mod trailer;
+mod verify;
+mod window;
 mod writer;
 
+#[cfg(test)]
+mod test_util;
+
 use human_bytes::human_bytes;
 use humantime::format_duration;
 use indicatif::ProgressBar;
@@ -30,18 +57,28 @@ fn main() -> anyhow::Result<()> {
     let args = get_args()?;
     debug!("args: {:?}", args);
 
+    // restoring an image doesn't involve reader threads
+    if args.restore {
+        print_digests(&restore::restore(&args)?);
+
+        let elapsed = start.elapsed();
+        info!("took: {}", format_duration(elapsed));
+        return Ok(());
+    }
+
+    if args.info {
+        return info::info(&args);
+    }
+
+    // verification exits with an error on mismatch
+    if args.verify {
+        return verify::verify(&args);
+    }
+
     // get device size
     let devsize = Device::size(&args.r#if)?;
     let pbar = Arc::new(ProgressBar::new(devsize));
 
-    // if this option is set, try to deduplicate only
-    // this will keep which blocks are only 0
-    let mut zero_dup = Vec::<u64>::new();
-
-    // key is hash, value is offset
-    let mut dedup = HashMap::<u128, u64>::new();
-    let mut dont_write = false;
-
     // we'll keep thred handles here
     let mut handles = Vec::new();
 
@@ -49,7 +86,60 @@ fn main() -> anyhow::Result<()> {
     let (tx, rx) = mpsc::channel::<(u64, Vec<u8>)>();
 
     // start our writer/hasher thread
-    let writer_params = WriterParams::from(&args);
+    let mut writer_params = WriterParams::from(&args);
+    writer_params.source_size = devsize;
+    writer_params.metadata = Metadata::try_from(&args)?;
+
+    // dictionary is trained before reading starts, so every chunk can use it
+    if args.dictionary {
+        let dictionary = Dictionary::train(
+            &args.r#if,
+            args.block_size(),
+            args.dict_sample().unwrap_or_default(),
+            args.level,
+        )?;
+        info!("trained a {} bytes dictionary", dictionary.as_bytes().len());
+        writer_params.dictionary = Some(Arc::new(dictionary));
+    }
+
+    // digests of the parent blocks are loaded before reading starts, to be compared with ours
+    if let Some(path) = &args.parent {
+        let parent = Parent::load(path, args.block_size(), args.parent_hashes.as_deref())?;
+        info!(
+            "parent: {} block hashes loaded for {}",
+            parent.len(),
+            parent.id.path
+        );
+        writer_params.parent = Some(Arc::new(parent));
+    }
+
+    // digests of the previous acquisition, compared with ours as blocks are read
+    if let Some(path) = &args.changed_since {
+        let previous = BlockHashes::read(path)?;
+        if previous.block_size != args.block_size() as u64 {
+            anyhow::bail!(
+                "sidecar {} has a block size of {} while block size is {}",
+                path.display(),
+                previous.block_size,
+                args.block_size()
+            );
+        }
+        // a single digest is computed per block, and the image only stores blake3 ones
+        if args.store_block_hashes() && previous.algorithm != BlockHash::Blake3 {
+            anyhow::bail!(
+                "sidecar {} holds {} digests while --block-hashes stores blake3 ones, write it \
+                 with --cbt-hash blake3",
+                path.display(),
+                previous.algorithm
+            );
+        }
+        writer_params.block_hash = Some(previous.algorithm);
+        writer_params.changed_since = Some(Arc::new(previous));
+    }
+
+    // read errors are counted by reader threads and recorded by writer thread
+    let errors = Arc::new(AtomicU64::new(0));
+    writer_params.errors = Arc::clone(&errors);
     let hasher_handle = writer_thread(rx, writer_params);
 
     info!(
@@ -63,7 +153,6 @@ fn main() -> anyhow::Result<()> {
 
     // new to synchronize access to offset for multi-threaded access
     let shared_offset = Arc::new(AtomicU64::new(0));
-    let block_index = Arc::new(AtomicU64::new(0));
 
     // start args.threads number of threads
     for i in 0..args.nb_threads() {
@@ -74,11 +163,12 @@ fn main() -> anyhow::Result<()> {
             nb_threads: args.nb_threads(),
             thread_id: i,
             block_size: args.block_size(),
+            source_size: devsize,
             pbar: Arc::clone(&pbar),
-            tx: tx,
+            tx,
             num_buffers: args.buffers,
             shared_offset: Arc::clone(&shared_offset),
-            block_index: Arc::clone(&block_index),
+            errors: Arc::clone(&errors),
             pattern_func: |n, i, k| n * k + i,
         };
         trace!("{:?}", ctx);
@@ -100,19 +190,35 @@ fn main() -> anyhow::Result<()> {
     }
 
     // print out hash if any
-    let hash = hasher_handle
+    let summary = hasher_handle
         .join()
-        .map_err(|e| anyhow::anyhow!("thread panicked: {:?}", e))?;
+        .map_err(|e| anyhow::anyhow!("thread panicked: {:?}", e))??;
 
-    if let Some(hash) = hash {
-        println!("{hash}");
+    // the Merkle root is printed along, a copy made in dd mode having nowhere to store it
+    let mut printed = summary.digests.clone();
+    if let Some(fingerprint) = &summary.fingerprint {
+        printed.push(("merkle".to_string(), fingerprint.clone()));
+    }
+    print_digests(&printed);
+
+    // sum files name what the digests can be checked against: the copy in dd mode, the source
+    // otherwise
+    if args.hash_files
+        && let Some(output) = &args.of
+    {
+        let name = if args.dd {
+            Path::new(output.file_name().unwrap_or_default())
+        } else {
+            args.r#if.as_path()
+        };
+        write_sum_files(output, &summary.digests, name)?;
     }
 
     //───────────────────────────────────────────────────────────────────────────────────
     // elapsed time
     //───────────────────────────────────────────────────────────────────────────────────
     pbar.finish();
-    
+
     let elapsed = start.elapsed();
     let rate = devsize as f64 / elapsed.as_secs_f64();
     info!(
@@ -121,5 +227,27 @@ fn main() -> anyhow::Result<()> {
         human_bytes(rate)
     );
 
+    // how blocks were stored, on stderr so stdout only holds digests
+    let chunks: Vec<_> = summary
+        .chunks
+        .iter()
+        .map(|(chunk_type, count)| format!("{chunk_type}:{count}"))
+        .collect();
+    eprintln!("blocks: {}", chunks.join(" "));
+
+    // changed ranges are the result of --changed-since
+    if let Some(changes) = &summary.changes {
+        let bs = args.block_size() as u64;
+        for (first, last) in changes {
+            println!(
+                "changed blocks {first}-{last} (bytes {}-{})",
+                first * bs,
+                ((last + 1) * bs).min(devsize) - 1
+            );
+        }
+        let changed: u64 = changes.iter().map(|(first, last)| last - first + 1).sum();
+        info!("{changed} changed blocks in {} ranges", changes.len());
+    }
+
     Ok(())
 }
@@ -0,0 +1,259 @@
+// Merkle tree over blocks of the source, so a single block read from an image can be checked
+// against the root without hashing the whole source. The root is the fingerprint of the image
+//
+// leaves are the blake3 hash of each block size bytes of the ordered stream, nodes the blake3
+// hash of their two children, an odd node being carried up as is. Only the level of nodes
+// covering GROUP_LEAVES leaves is stored, upper levels being cheap to rebuild from it.
+//
+// section layout (big-endian):
+//
+// leaf size (8) | leaves (8) | group leaves (8) | root (32) | node (32) * groups
+use std::io::{Read, Write};
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::hash::hex;
+
+// leaves under a stored node: checking a block means hashing this many blocks
+pub const GROUP_LEAVES: u64 = 16;
+
+const HASH_LEN: usize = 32;
+const FIXED_LEN: usize = 8 + 8 + 8 + HASH_LEN;
+
+pub type Hash = [u8; HASH_LEN];
+
+// leaves and nodes are told apart so a node can't pass for a leaf
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0]);
+    hasher.update(data);
+    *hasher.finalize().as_bytes()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[1]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+// root of the tree over these hashes, level by level
+pub fn root_of(hashes: &[Hash]) -> Hash {
+    if hashes.is_empty() {
+        return leaf_hash(&[]);
+    }
+
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                _ => pair[0],
+            })
+            .collect();
+    }
+    level[0]
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MerkleTree {
+    pub leaf_size: u64,
+    pub leaves: u64,
+
+    // nodes each covering GROUP_LEAVES leaves, the last one maybe less
+    #[serde(skip)]
+    pub nodes: Vec<Hash>,
+
+    // fingerprint of the source
+    #[serde(serialize_with = "serialize_hash")]
+    pub root: Hash,
+}
+
+impl MerkleTree {
+    // write section, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        dst.write_all(&self.leaf_size.to_be_bytes())?;
+        dst.write_all(&self.leaves.to_be_bytes())?;
+        dst.write_all(&GROUP_LEAVES.to_be_bytes())?;
+        dst.write_all(&self.root)?;
+        for node in &self.nodes {
+            dst.write_all(node)?;
+        }
+
+        Ok(FIXED_LEN + self.nodes.len() * HASH_LEN)
+    }
+
+    // read a section of len bytes, checking its nodes give its root
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        let mut buf = vec![0u8; len as usize];
+        src.read_exact(&mut buf)?;
+
+        if buf.len() < FIXED_LEN || !(buf.len() - FIXED_LEN).is_multiple_of(HASH_LEN) {
+            bail!("corrupted Merkle tree: {} bytes", buf.len());
+        }
+        let leaf_size = u64::from_be_bytes(buf[0..8].try_into()?);
+        let leaves = u64::from_be_bytes(buf[8..16].try_into()?);
+        let group_leaves = u64::from_be_bytes(buf[16..24].try_into()?);
+        if group_leaves != GROUP_LEAVES {
+            bail!("unsupported Merkle tree of {group_leaves} leaves per stored node");
+        }
+
+        let tree = Self {
+            leaf_size,
+            leaves,
+            root: buf[24..FIXED_LEN].try_into()?,
+            nodes: buf[FIXED_LEN..]
+                .chunks(HASH_LEN)
+                .map(|node| node.try_into())
+                .collect::<Result<_, _>>()?,
+        };
+
+        if tree.nodes.len() as u64 != leaves.div_ceil(GROUP_LEAVES) {
+            bail!(
+                "corrupted Merkle tree: {} nodes for {leaves} leaves",
+                tree.nodes.len()
+            );
+        }
+        if root_of(&tree.nodes) != tree.root {
+            bail!("corrupted Merkle tree: nodes don't give its root");
+        }
+
+        Ok(tree)
+    }
+
+    // first group of blocks, as (first, last), which doesn't match the one of other
+    pub fn first_mismatch(&self, other: &MerkleTree) -> Option<(u64, u64)> {
+        let group = (0..self.nodes.len().max(other.nodes.len()))
+            .find(|i| self.nodes.get(*i) != other.nodes.get(*i))? as u64;
+
+        let first = group * GROUP_LEAVES;
+        let last = (first + GROUP_LEAVES).min(self.leaves.max(other.leaves)) - 1;
+        Some((first, last))
+    }
+}
+
+// builds the tree from the ordered stream
+pub struct MerkleBuilder {
+    leaf_size: u64,
+    leaves: u64,
+
+    // current leaf and bytes hashed in it
+    leaf: blake3::Hasher,
+    len: u64,
+
+    // leaves of the current group, and nodes of the groups done
+    group: Vec<Hash>,
+    nodes: Vec<Hash>,
+}
+
+impl MerkleBuilder {
+    pub fn new(leaf_size: u64) -> Self {
+        Self {
+            leaf_size,
+            leaves: 0,
+            leaf: Self::new_leaf(),
+            len: 0,
+            group: Vec::with_capacity(GROUP_LEAVES as usize),
+            nodes: Vec::new(),
+        }
+    }
+
+    // feed next data of the stream, which can span several leaves
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (self.leaf_size - self.len).min(data.len() as u64) as usize;
+            self.leaf.update(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+
+            if self.len == self.leaf_size {
+                self.close_leaf();
+            }
+        }
+    }
+
+    // close the last leaf and group, which might be shorter
+    pub fn finish(mut self) -> MerkleTree {
+        if self.len > 0 {
+            self.close_leaf();
+        }
+        if !self.group.is_empty() {
+            self.nodes.push(root_of(&self.group));
+        }
+
+        MerkleTree {
+            leaf_size: self.leaf_size,
+            leaves: self.leaves,
+            root: root_of(&self.nodes),
+            nodes: self.nodes,
+        }
+    }
+
+    fn new_leaf() -> blake3::Hasher {
+        let mut leaf = blake3::Hasher::new();
+        leaf.update(&[0]);
+        leaf
+    }
+
+    fn close_leaf(&mut self) {
+        let leaf = std::mem::replace(&mut self.leaf, Self::new_leaf());
+        self.group.push(*leaf.finalize().as_bytes());
+        self.leaves += 1;
+        self.len = 0;
+
+        if self.group.len() as u64 == GROUP_LEAVES {
+            self.nodes.push(root_of(&self.group));
+            self.group.clear();
+        }
+    }
+}
+
+fn serialize_hash<S: serde::Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree() -> anyhow::Result<()> {
+        let blocks: Vec<Vec<u8>> = (0..37u8).map(|i| vec![i; 512]).collect();
+        let data = blocks.concat();
+
+        let build = |data: &[u8], piece: usize| {
+            let mut builder = MerkleBuilder::new(512);
+            data.chunks(piece).for_each(|p| builder.update(p));
+            builder.finish()
+        };
+        let tree = build(&data, 1000);
+        assert_eq!(build(&data, data.len()), tree);
+        assert_eq!(tree.leaves, 37);
+        assert_eq!(tree.nodes.len(), 3);
+
+        // storing groups doesn't change the root of the whole tree
+        let leaves: Vec<_> = blocks.iter().map(|b| leaf_hash(b)).collect();
+        assert_eq!(tree.root, root_of(&leaves));
+
+        // a block is checked with its group only
+        assert_eq!(root_of(&leaves[16..32]), tree.nodes[1]);
+
+        let mut buf = Vec::new();
+        let n = tree.write(&mut buf)?;
+        assert_eq!(n, buf.len());
+        assert_eq!(MerkleTree::read(&mut buf.as_slice(), n as u64)?, tree);
+
+        buf[FIXED_LEN] ^= 1;
+        assert!(MerkleTree::read(&mut buf.as_slice(), n as u64).is_err());
+
+        let mut damaged = data.clone();
+        damaged[20 * 512] ^= 1;
+        assert_eq!(tree.first_mismatch(&build(&damaged, 512)), Some((16, 31)));
+        assert_eq!(tree.first_mismatch(&tree), None);
+
+        Ok(())
+    }
+}
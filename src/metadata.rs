@@ -0,0 +1,185 @@
+// case metadata stored in a dedicated section of the image, for chain-of-custody
+//
+// layout (big-endian):
+//
+// count (4) | (key length (2) | key | value length (4) | value) * count
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, anyhow, bail};
+use serde::{Serialize, Serializer, ser::SerializeMap};
+
+use crate::args::Args;
+
+// key/value pairs, in the order they were given
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metadata(Vec<(String, String)>);
+
+impl Metadata {
+    // set a value, replacing the previous one for the same key
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.0.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.0.iter()
+    }
+
+    // read key = value lines from a file. Empty lines and lines starting with # are skipped
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("unable to read metadata file {}", path.display()))?;
+
+        let mut metadata = Self::default();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{}:{}: expected key = value", path.display(), n + 1))?;
+            metadata.set(key.trim(), value.trim());
+        }
+
+        Ok(metadata)
+    }
+
+    // write metadata section, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        let mut written = 4;
+        dst.write_all(&(self.0.len() as u32).to_be_bytes())?;
+
+        for (key, value) in &self.0 {
+            let key_len =
+                u16::try_from(key.len()).map_err(|_| anyhow!("metadata key '{key}' too long"))?;
+
+            dst.write_all(&key_len.to_be_bytes())?;
+            dst.write_all(key.as_bytes())?;
+            dst.write_all(&(value.len() as u32).to_be_bytes())?;
+            dst.write_all(value.as_bytes())?;
+
+            written += 2 + key.len() + 4 + value.len();
+        }
+
+        Ok(written)
+    }
+
+    // read a metadata section of len bytes
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        let mut src = src.take(len);
+
+        let count = read_u32(&mut src)?;
+        let mut metadata = Self::default();
+
+        for _ in 0..count {
+            let mut key_len = [0u8; 2];
+            src.read_exact(&mut key_len)?;
+            let key = read_string(&mut src, u16::from_be_bytes(key_len) as u64)?;
+
+            let value_len = read_u32(&mut src)?;
+            let value = read_string(&mut src, value_len as u64)?;
+
+            metadata.0.push((key, value));
+        }
+
+        if src.limit() != 0 {
+            bail!("corrupted metadata: {} trailing bytes", src.limit());
+        }
+
+        Ok(metadata)
+    }
+}
+
+// metadata given on the command line, optionally on top of a metadata file
+impl TryFrom<&Args> for Metadata {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &Args) -> Result<Self, Self::Error> {
+        let mut metadata = match &args.metadata {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        let options = [
+            ("case_number", &args.case_number),
+            ("examiner", &args.examiner),
+            ("evidence_id", &args.evidence_id),
+            ("description", &args.description),
+            ("notes", &args.notes),
+        ];
+        for (key, value) in options {
+            if let Some(value) = value {
+                metadata.set(key, value);
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+// serialized as a JSON object, keeping order
+impl Serialize for Metadata {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+fn read_u32<R: Read>(src: &mut R) -> anyhow::Result<u32> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf)
+        .map_err(|e| anyhow!("corrupted metadata: {e}"))?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_string<R: Read>(src: &mut R, len: u64) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    src.take(len).read_to_end(&mut buf)?;
+
+    if buf.len() as u64 != len {
+        bail!("corrupted metadata: string truncated");
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let mut metadata = Metadata::default();
+        metadata.set("case_number", "2026-042");
+        metadata.set("examiner", "J. Doe");
+        metadata.set("notes", "seized at desk #3\nsecond line");
+        metadata.set("examiner", "A. Smith");
+
+        let mut buf = Vec::new();
+        let n = metadata.write(&mut buf)?;
+        assert_eq!(n, buf.len());
+
+        let read = Metadata::read(&mut buf.as_slice(), n as u64)?;
+        assert_eq!(read, metadata);
+        assert_eq!(read.iter().nth(1).unwrap().1, "A. Smith");
+
+        // truncated section
+        assert!(Metadata::read(&mut buf.as_slice(), n as u64 - 1).is_err());
+
+        Ok(())
+    }
+}
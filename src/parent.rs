@@ -0,0 +1,192 @@
+// parent of an incremental image: a previous acquisition of the same source, whose blocks
+// are referenced instead of being stored again when they didn't change
+use std::{
+    io::{self, Read},
+    mem,
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use log::warn;
+use serde::Serialize;
+
+use crate::{
+    cbt::{BlockHash, BlockHashes},
+    hash::{HashAlgorithm, Hashes},
+    header::ImageHeader,
+    image_reader::ImageReader,
+    info::{read_block_hashes, read_trailer},
+    segment::SegmentReader,
+};
+
+// how an incremental image identifies its parent: where it is and the digest of its source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParentRef {
+    pub path: String,
+
+    // as ALGO:DIGEST, taken from the parent trailer
+    pub digest: String,
+}
+
+impl ParentRef {
+    // identity of the image at path, which must have a digest in its trailer
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("unable to open parent image {}", path.display()))?;
+
+        let trailer = read_trailer(&mut SegmentReader::open(&path)?)?
+            .with_context(|| format!("parent image {} was not completed", path.display()))?;
+        if !trailer.completed {
+            warn!("parent image {} is incomplete", path.display());
+        }
+
+        let (algorithm, digest) = trailer
+            .digests
+            .iter()
+            .find(|(algorithm, _)| HashAlgorithm::from_name(algorithm).is_some())
+            .with_context(|| {
+                format!(
+                    "parent image {} has no digest, it must be acquired with --hash",
+                    path.display()
+                )
+            })?;
+
+        Ok(Self {
+            path: path.display().to_string(),
+            digest: format!("{algorithm}:{digest}"),
+        })
+    }
+
+    // algorithm and digest of the parent source
+    fn digest(&self) -> anyhow::Result<(HashAlgorithm, &str)> {
+        self.digest
+            .split_once(':')
+            .and_then(|(algorithm, digest)| Some((HashAlgorithm::from_name(algorithm)?, digest)))
+            .with_context(|| format!("unsupported parent digest {}", self.digest))
+    }
+
+    // check the image found at path is still the one recorded
+    pub fn check(&self) -> anyhow::Result<()> {
+        let found = Self::of(Path::new(&self.path))?;
+        if found.digest != self.digest {
+            bail!(
+                "parent image {} doesn't match: {} found instead of {}",
+                self.path,
+                found.digest,
+                self.digest
+            );
+        }
+        Ok(())
+    }
+}
+
+// parent image read along an incremental one, its content being hashed to make sure it still
+// gives the digest recorded when the incremental image was written
+pub struct ParentReader {
+    id: ParentRef,
+    reader: Box<dyn Read>,
+    hashes: Hashes,
+}
+
+impl ParentReader {
+    // check the trailer of the parent, then get ready to decode it
+    pub fn open(id: &ParentRef) -> anyhow::Result<Self> {
+        id.check()?;
+        let (algorithm, _) = id.digest()?;
+        let reader = ImageReader::open(SegmentReader::open(Path::new(&id.path))?)?;
+
+        Ok(Self {
+            id: id.clone(),
+            reader: Box::new(reader),
+            hashes: Hashes::new(&[algorithm]),
+        })
+    }
+
+    // read what's left of the parent and compare its digest with the recorded one
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        io::copy(self, &mut io::sink())?;
+
+        let (_, expected) = self.id.digest()?;
+        let found = mem::take(&mut self.hashes).digests();
+        if found.first().map(|(_, d)| d.as_str()) != Some(expected) {
+            bail!(
+                "parent image {} doesn't match: its content doesn't give {}",
+                self.id.path,
+                self.id.digest
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Read for ParentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hashes.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// what the writer needs to know of the parent
+#[derive(Debug)]
+pub struct Parent {
+    pub id: ParentRef,
+
+    // blake3 digest of each block of the parent source
+    hashes: BlockHashes,
+}
+
+impl Parent {
+    // load the block digests of the parent image, which must have the same block size. They're
+    // stored in the image, or in the sidecar written along with it
+    pub fn load(path: &Path, block_size: usize, sidecar: Option<&Path>) -> anyhow::Result<Self> {
+        let id = ParentRef::of(path)?;
+
+        let mut image = SegmentReader::open(Path::new(&id.path))?;
+        let header = ImageHeader::read(&mut image)?;
+        if header.cdc {
+            bail!("parent image is made of content-defined chunks, not blocks");
+        }
+        if header.block_size != block_size as u64 {
+            bail!(
+                "parent image block size is {} while block size is {block_size}",
+                header.block_size
+            );
+        }
+
+        let hashes = match sidecar {
+            Some(sidecar) => BlockHashes::read(sidecar)?,
+            None => read_block_hashes(&mut image)?.with_context(|| {
+                format!(
+                    "parent image {} has no block hashes, it must be acquired with --block-hashes or given its sidecar with --parent-hashes",
+                    id.path
+                )
+            })?,
+        };
+        if hashes.algorithm != BlockHash::Blake3 {
+            bail!(
+                "parent block hashes must be blake3, not {}",
+                hashes.algorithm
+            );
+        }
+        if hashes.block_size != header.block_size || hashes.source_size != header.source_size {
+            bail!(
+                "parent block hashes are for {} bytes blocks of a {} bytes source, not the ones of the parent image",
+                hashes.block_size,
+                hashes.source_size
+            );
+        }
+
+        Ok(Self { id, hashes })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.hashes.len()
+    }
+
+    // true if block has the same blake3 digest in the parent
+    pub fn unchanged(&self, block: u64, digest: &[u8]) -> bool {
+        self.hashes.get(block) == Some(digest)
+    }
+}
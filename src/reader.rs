@@ -1,6 +1,6 @@
-use std::iter;
 use std::os::unix::fs::OpenOptionsExt;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::{io, iter};
 use std::{
     path::PathBuf,
     sync::{Arc, mpsc::Sender},
@@ -11,13 +11,13 @@ use anyhow::Context;
 use futures::StreamExt;
 use indicatif::ProgressBar;
 use libc::{O_DIRECT, O_SYNC};
-use log::{debug, info, trace};
-use tokio_uring::buf::fixed::FixedBufRegistry;
+use log::{debug, error};
+use tokio_uring::buf::fixed::{FixedBuf, FixedBufRegistry};
 use tokio_uring::buf::{IoBuf, IoBufMut};
-use tokio_uring::fs::OpenOptions;
+use tokio_uring::fs::{File, OpenOptions};
 
 type AlignedVector = AVec<u8, ConstAlign<4096>>;
-struct AlignedWrapper(AlignedVector);
+pub struct AlignedWrapper(AlignedVector);
 impl AlignedWrapper {
     pub fn init(len: usize) -> Self {
         let v = avec![[4096] | 0u8; len];
@@ -55,6 +55,7 @@ unsafe impl IoBufMut for AlignedWrapper {
 // a context contains all what is necessary to apply a specific pattern
 // when reading blocks using multiple threads
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct RunContext {
     // number of threads
     pub nb_threads: usize,
@@ -65,6 +66,9 @@ pub struct RunContext {
     // block size passed from arguments
     pub block_size: usize,
 
+    // size of the source, a failed read is only padded up to it
+    pub source_size: u64,
+
     // progress bar shared between threads
     pub pbar: Arc<ProgressBar>,
 
@@ -77,8 +81,8 @@ pub struct RunContext {
     // this will be incremented by all threads
     pub shared_offset: Arc<AtomicU64>,
 
-    // used to sync block
-    pub block_index: Arc<AtomicU64>,
+    // number of failed reads, shared with writer thread
+    pub errors: Arc<AtomicU64>,
 
     // function giving the block to read
     pub pattern_func: fn(nb_threads: usize, thread_id: usize, k: usize) -> usize,
@@ -88,7 +92,7 @@ pub struct RunContext {
 pub fn read_par(ctx: RunContext, path: PathBuf) -> anyhow::Result<()> {
     debug!("tokio-uring runtime started");
 
-    tokio_uring::start(async {
+    let res = tokio_uring::start(async {
         // build our aligned buffer registry abd register to the kernel
         let registry = FixedBufRegistry::new(
             iter::repeat_with(|| AlignedWrapper::init(ctx.block_size)).take(ctx.num_buffers),
@@ -122,40 +126,85 @@ pub fn read_par(ctx: RunContext, path: PathBuf) -> anyhow::Result<()> {
                 .shared_offset
                 .fetch_add(ctx.block_size as u64, Ordering::Relaxed);
 
-            active_reads.push(src.read_fixed_at(buf, offset));
+            active_reads.push(read_block_at(&src, buf, offset));
 
             //offset += ctx.block_size as u64;
         }
 
-        while let Some((res, buf)) = active_reads.next().await {
-            let bytes_read = res?;
-
+        while let Some((offset, (res, buf))) = active_reads.next().await {
             // continue but not break: outstanding buffers might contain data
-            if bytes_read == 0 {
+            let Some(block) = completed_read(&ctx, offset, res, &buf) else {
                 continue;
-            }
+            };
+            let full = block.len() == ctx.block_size;
 
-            // actual reads happen here
-            // send data to our writer/hasher thread
-            ctx.pbar.inc(bytes_read as u64);
-
-            let index = ctx.block_index.fetch_add(1, Ordering::Relaxed);
+            // block index is given by the offset, whatever the order reads complete
+            let index = offset / ctx.block_size as u64;
             // println!("threadID:{} thread_offset={thread_offset}", ctx.thread_id);
-            ctx.tx.send((index, buf[..bytes_read].to_vec()))?;
+            ctx.tx.send((index, block))?;
 
-            if bytes_read == ctx.block_size {
+            // a short read means end of source, but outstanding reads must still be drained
+            if full {
                 let offset = ctx
                     .shared_offset
                     .fetch_add(ctx.block_size as u64, Ordering::Relaxed);
-                active_reads.push(src.read_fixed_at(buf, offset));
+                active_reads.push(read_block_at(&src, buf, offset));
                 //offset += ctx.block_size as u64;
-            } else {
-                break;
             }
         }
 
         Ok(())
-    })
+    });
+
+    if let Err(e) = &res {
+        error!("thread {}: {e}", ctx.thread_id);
+    }
+
+    res
+}
+
+// data of a completed read, or zeros if the read failed so that the blocks following it can
+// still be written in order, like dd conv=noerror,sync. None at end of source
+fn completed_read(
+    ctx: &RunContext,
+    offset: u64,
+    res: io::Result<usize>,
+    buf: &[u8],
+) -> Option<Vec<u8>> {
+    match res {
+        Ok(0) => None,
+        Ok(bytes_read) => {
+            ctx.pbar.inc(bytes_read as u64);
+            Some(buf[..bytes_read].to_vec())
+        }
+        Err(e) => {
+            // past the end of the source, there is nothing to pad
+            let len = ctx
+                .source_size
+                .saturating_sub(offset)
+                .min(ctx.block_size as u64);
+            if len == 0 {
+                return None;
+            }
+
+            error!(
+                "thread {}: read failed at offset {offset}: {e}",
+                ctx.thread_id
+            );
+            ctx.errors.fetch_add(1, Ordering::Relaxed);
+            ctx.pbar.inc(len);
+            Some(vec![0u8; len as usize])
+        }
+    }
+}
+
+// read a block and keep track of its offset
+async fn read_block_at(
+    src: &File,
+    buf: FixedBuf,
+    offset: u64,
+) -> (u64, tokio_uring::BufResult<usize, FixedBuf>) {
+    (offset, src.read_fixed_at(buf, offset).await)
 }
 
 // // the indicates how block are read: round-robin, contiguously, etc
@@ -170,3 +219,127 @@ pub fn read_par(ctx: RunContext, path: PathBuf) -> anyhow::Result<()> {
 //     // Thread 3 → B3, B7, B11...
 //     round_robin
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, sync::mpsc, thread};
+
+    use super::*;
+    use crate::{
+        image_reader::ImageReader,
+        info::read_trailer,
+        segment::SegmentReader,
+        test_util::{TempDir, random_bytes, write_image},
+        writer::WriterParams,
+    };
+
+    #[test]
+    fn blocks_by_offset() -> anyhow::Result<()> {
+        const BLOCK_SIZE: usize = 4096;
+
+        // every block is different, and the last one is short
+        let data: Vec<u8> = (0..64 * BLOCK_SIZE + 1000)
+            .map(|i| (i / BLOCK_SIZE + i % 251) as u8)
+            .collect();
+        let dir = TempDir::new("reader");
+        let path = dir.join("source.bin");
+        fs::write(&path, &data)?;
+
+        let (tx, rx) = mpsc::channel();
+        let shared_offset = Arc::new(AtomicU64::new(0));
+        let errors = Arc::new(AtomicU64::new(0));
+        let handles: Vec<_> = (0..2)
+            .map(|thread_id| {
+                let ctx = RunContext {
+                    nb_threads: 2,
+                    thread_id,
+                    block_size: BLOCK_SIZE,
+                    source_size: data.len() as u64,
+                    pbar: Arc::new(ProgressBar::hidden()),
+                    tx: tx.clone(),
+                    num_buffers: 8,
+                    shared_offset: Arc::clone(&shared_offset),
+                    errors: Arc::clone(&errors),
+                    pattern_func: |n, i, k| n * k + i,
+                };
+                let path = path.clone();
+                thread::spawn(move || read_par(ctx, path))
+            })
+            .collect();
+        drop(tx);
+
+        let mut blocks: Vec<(u64, Vec<u8>)> = rx.iter().collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        // all blocks are read, each one numbered after its offset
+        blocks.sort();
+        assert_eq!(blocks.len(), 65);
+        for (expected, (index, block)) in data.chunks(BLOCK_SIZE).zip(&blocks) {
+            assert_eq!(block, expected, "block {index}");
+        }
+        assert_eq!(errors.load(Ordering::Relaxed), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_error() -> anyhow::Result<()> {
+        const BLOCK_SIZE: usize = 4096;
+
+        let data = random_bytes(8 * BLOCK_SIZE);
+        let dir = TempDir::new("read_error");
+        let path = dir.join("image.img");
+
+        let errors = Arc::new(AtomicU64::new(0));
+        let params = WriterParams {
+            output_file: Some(path.clone()),
+            block_size: BLOCK_SIZE,
+            source_size: data.len() as u64,
+            errors: Arc::clone(&errors),
+            nb_workers: 4,
+            ..Default::default()
+        };
+        // blocks are given back by completed_read rather than sent
+        let (tx, _rx) = mpsc::channel();
+        let ctx = RunContext {
+            nb_threads: 1,
+            thread_id: 0,
+            block_size: BLOCK_SIZE,
+            source_size: data.len() as u64,
+            pbar: Arc::new(ProgressBar::hidden()),
+            tx,
+            num_buffers: 1,
+            shared_offset: Arc::new(AtomicU64::new(0)),
+            errors: Arc::clone(&errors),
+            pattern_func: |n, i, k| n * k + i,
+        };
+
+        // block 3 can't be read, and neither can anything past the source
+        let mut blocks = Vec::new();
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate().chain([(8, &[][..])]) {
+            let res = match i {
+                3 | 8 => Err(io::Error::from_raw_os_error(libc::EIO)),
+                _ => Ok(block.len()),
+            };
+            let offset = (i * BLOCK_SIZE) as u64;
+            blocks.extend(completed_read(&ctx, offset, res, block));
+        }
+        write_image(params, blocks.iter().map(Vec::as_slice))?;
+
+        // the unreadable block is replaced by zeros, the following ones are still written
+        let mut decoded = Vec::new();
+        ImageReader::open(SegmentReader::open(&path)?)?.read_to_end(&mut decoded)?;
+        let mut expected = data.clone();
+        expected[3 * BLOCK_SIZE..4 * BLOCK_SIZE].fill(0);
+        assert!(decoded == expected);
+
+        let trailer = read_trailer(&mut SegmentReader::open(&path)?)?.unwrap();
+        assert_eq!(trailer.errors, 1);
+        assert_eq!(trailer.bytes_read, data.len() as u64);
+        assert!(!trailer.completed);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+// content-addressable chunk repository shared by many acquisitions
+//
+// chunks are kept once, as chunk records named by the blake3 hash of their original data:
+//
+// DIR/chunks/<first 2 hex digits>/<other 62 hex digits>
+//
+// each acquisition only writes a manifest: a dimg image whose data chunks are replaced by the
+// hash of the chunk in the repository. Acquisitions are appended to DIR/acquisitions.jsonl so
+// the repository knows how many logical bytes it holds
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow, bail};
+use human_bytes::human_bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::{Chunk, ChunkType, RECORD_HEADER_LEN},
+    hash::hex,
+};
+
+// key of a chunk: blake3 hash of its original data
+pub type ChunkKey = [u8; 32];
+
+const CHUNKS_DIR: &str = "chunks";
+const ACQUISITIONS_FILE: &str = "acquisitions.jsonl";
+
+#[derive(Debug)]
+pub struct Repository {
+    path: PathBuf,
+}
+
+// an acquisition which stored its chunks in the repository
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Acquisition {
+    // manifest written for it
+    pub manifest: String,
+
+    // size of the source
+    pub source_size: u64,
+
+    // chunks which were not already in the repository, and their stored size
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+
+    // end of acquisition, as seconds since UNIX epoch
+    pub end_time: u64,
+}
+
+// what the repository holds
+#[derive(Debug, Default, Serialize)]
+pub struct RepositoryStats {
+    pub repository: String,
+    pub acquisitions: u64,
+
+    // size of all sources acquired
+    pub logical_bytes: u64,
+
+    // chunks kept and their size on disk
+    pub chunks: u64,
+    pub stored_bytes: u64,
+
+    // logical bytes / stored bytes
+    pub dedup_ratio: f64,
+}
+
+impl Repository {
+    // create the repository if it doesn't exist yet
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(path.join(CHUNKS_DIR))
+            .with_context(|| format!("unable to create repository {}", path.display()))?;
+        Self::open(path)
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if !path.join(CHUNKS_DIR).is_dir() {
+            bail!("{} is not a dimg repository", path.display());
+        }
+
+        Ok(Self {
+            path: path
+                .canonicalize()
+                .with_context(|| format!("unable to open repository {}", path.display()))?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // store a chunk record unless already there, returning its size if it was written
+    pub fn put(&self, key: &ChunkKey, chunk: &Chunk) -> anyhow::Result<Option<u64>> {
+        let path = self.chunk_path(key);
+        if path.exists() {
+            return Ok(None);
+        }
+
+        let mut record = Vec::with_capacity(chunk.encoded_len());
+        chunk.write(&mut record)?;
+
+        // written aside first, so a chunk is never seen half written by another acquisition
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, &record)
+            .with_context(|| format!("unable to write chunk {}", tmp.display()))?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(Some(record.len() as u64))
+    }
+
+    // chunk type and data of the record stored for a key
+    pub fn get(&self, key: &ChunkKey) -> anyhow::Result<(ChunkType, Vec<u8>)> {
+        let path = self.chunk_path(key);
+        let mut record = fs::read(&path).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => anyhow!("chunk {} not found in repository", hex(key)),
+            _ => anyhow!("unable to read chunk {}: {e}", path.display()),
+        })?;
+
+        if record.len() < RECORD_HEADER_LEN {
+            bail!("corrupted chunk {}: only {} bytes", hex(key), record.len());
+        }
+        let len = u64::from_be_bytes(record[0..8].try_into()?);
+        let chunk_type = ChunkType::try_from(record[8])?;
+        if len != (record.len() - RECORD_HEADER_LEN) as u64 {
+            bail!(
+                "corrupted chunk {}: {len} bytes expected, {} found",
+                hex(key),
+                record.len() - RECORD_HEADER_LEN
+            );
+        }
+
+        Ok((chunk_type, record.split_off(RECORD_HEADER_LEN)))
+    }
+
+    // add an acquisition to the log
+    pub fn record(&self, acquisition: &Acquisition) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(acquisition)?;
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.join(ACQUISITIONS_FILE))?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // walk chunks and acquisitions to sum what's stored
+    pub fn stats(&self) -> anyhow::Result<RepositoryStats> {
+        let mut stats = RepositoryStats {
+            repository: self.path.display().to_string(),
+            ..Default::default()
+        };
+
+        for dir in fs::read_dir(self.path.join(CHUNKS_DIR))? {
+            for entry in fs::read_dir(dir?.path())? {
+                let entry = entry?;
+                if entry.path().extension().is_none() {
+                    stats.chunks += 1;
+                    stats.stored_bytes += entry.metadata()?.len();
+                }
+            }
+        }
+
+        match File::open(self.path.join(ACQUISITIONS_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let acquisition: Acquisition = serde_json::from_str(&line?)?;
+                    stats.acquisitions += 1;
+                    stats.logical_bytes += acquisition.source_size;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        if stats.stored_bytes > 0 {
+            stats.dedup_ratio = stats.logical_bytes as f64 / stats.stored_bytes as f64;
+        }
+
+        Ok(stats)
+    }
+
+    // chunk files are named after the hex form of their key
+    fn chunk_path(&self, key: &ChunkKey) -> PathBuf {
+        let hex = hex(key);
+        self.path.join(CHUNKS_DIR).join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+impl fmt::Display for RepositoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<20}{}", "repository:", self.repository)?;
+        writeln!(f, "{:<20}{}", "acquisitions:", self.acquisitions)?;
+        writeln!(
+            f,
+            "{:<20}{} ({})",
+            "logical bytes:",
+            self.logical_bytes,
+            human_bytes(self.logical_bytes as f64)
+        )?;
+        writeln!(f, "{:<20}{}", "chunks:", self.chunks)?;
+        writeln!(
+            f,
+            "{:<20}{} ({})",
+            "stored bytes:",
+            self.stored_bytes,
+            human_bytes(self.stored_bytes as f64)
+        )?;
+        write!(f, "{:<20}{:.2}", "dedup ratio:", self.dedup_ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn put_get() -> anyhow::Result<()> {
+        let dir = TempDir::new("repo");
+        let repository = Repository::create(&dir.join("repo"))?;
+
+        let data = b"hello world".repeat(100);
+        let key = *blake3::hash(&data).as_bytes();
+
+        // a chunk is only stored once
+        let n = repository.put(&key, &Chunk::raw(&data))?;
+        assert_eq!(n, Some((RECORD_HEADER_LEN + data.len()) as u64));
+        assert_eq!(repository.put(&key, &Chunk::raw(&data))?, None);
+        assert_eq!(repository.get(&key)?, (ChunkType::Raw, data));
+        assert!(repository.get(&[0u8; 32]).is_err());
+
+        repository.record(&Acquisition {
+            manifest: "m1".to_string(),
+            source_size: 3000,
+            new_chunks: 1,
+            new_bytes: n.unwrap(),
+            end_time: 0,
+        })?;
+        let stats = repository.stats()?;
+        assert_eq!(stats.chunks, 1);
+        assert_eq!(stats.logical_bytes, 3000);
+        assert_eq!(stats.stored_bytes, n.unwrap());
+        Ok(())
+    }
+}
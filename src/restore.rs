@@ -0,0 +1,273 @@
+// restore module: expand a dimg image back onto a file or block device
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    iter,
+    os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt},
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use libc::O_DIRECT;
+use log::{debug, info, warn};
+use tokio_uring::buf::{
+    BoundedBuf,
+    fixed::{FixedBuf, FixedBufRegistry},
+};
+use tokio_uring::fs::OpenOptions;
+
+use crate::{
+    args::Args, device::Device, hash::HashPool, header::ImageHeader, image_reader::ImageReader,
+    reader::AlignedWrapper, repository::Repository, segment::SegmentReader,
+};
+
+// O_DIRECT writes must be aligned on this
+const ALIGNMENT: usize = 4096;
+
+// decode the image given by --if and write it onto --of, returning the restore-side digests
+pub fn restore(args: &Args) -> anyhow::Result<Vec<(String, String)>> {
+    let target = args
+        .of
+        .as_ref()
+        .context("no target given to restore onto")?;
+
+    let image = SegmentReader::open(&args.r#if)?;
+    let mut decoder = ImageReader::open(image)?;
+    if let Some(repo) = &args.repo {
+        decoder.set_repository(Repository::open(repo)?);
+    }
+    if let Some(parent) = &args.parent {
+        decoder.set_parent(parent)?;
+    }
+    let header = decoder.header().clone();
+    debug!("header: {:?}", header);
+
+    prepare_target(target, &header, args.force)?;
+
+    // hash what's written to compare with acquisition hash
+    let mut algorithms = args.hash_algorithms();
+    algorithms.extend(&header.hashes);
+    algorithms.sort();
+    algorithms.dedup();
+    let mut hashes = HashPool::new(&algorithms);
+    let pbar = ProgressBar::new(header.source_size);
+
+    let block_size = header.block_size as usize;
+    if !block_size.is_multiple_of(ALIGNMENT) {
+        bail!(
+            "block size {block_size} is not a multiple of {ALIGNMENT}, can't restore using O_DIRECT"
+        );
+    }
+
+    info!(
+        "image:{} target:{} block_size:{block_size} buffers:{} source_size:{}",
+        args.r#if.display(),
+        target.display(),
+        args.buffers,
+        header.source_size
+    );
+
+    // last block might not be aligned, so it's written without O_DIRECT
+    let mut tail: Option<(u64, Vec<u8>)> = None;
+
+    tokio_uring::start(async {
+        // same aligned buffer registry as readers
+        let registry = FixedBufRegistry::new(
+            iter::repeat_with(|| AlignedWrapper::init(block_size)).take(args.buffers),
+        );
+        registry.register()?;
+
+        let dst = OpenOptions::new()
+            .write(true)
+            .custom_flags(O_DIRECT)
+            .open(target)
+            .await?;
+
+        // all buffers are free at start
+        let mut free = Vec::with_capacity(args.buffers);
+        for i in 0..args.buffers {
+            free.push(
+                registry
+                    .check_out(i)
+                    .context("error checking out buffer from registry")?,
+            );
+        }
+
+        let mut active_writes = futures::stream::FuturesUnordered::new();
+        let mut offset = 0u64;
+
+        // content-defined chunks are of any size, so data is written by blocks of block size
+        loop {
+            let mut data = Vec::with_capacity(block_size);
+            (&mut decoder)
+                .take(block_size as u64)
+                .read_to_end(&mut data)?;
+            if data.is_empty() {
+                break;
+            }
+
+            hashes.update(&data);
+            pbar.inc(data.len() as u64);
+
+            let len = data.len();
+            if !len.is_multiple_of(ALIGNMENT) {
+                tail = Some((offset, data));
+                offset += len as u64;
+                continue;
+            }
+
+            // wait for a buffer to be available
+            let mut buf = match free.pop() {
+                Some(buf) => buf,
+                None => {
+                    let (res, buf) = active_writes.next().await.context("no write in flight")?;
+                    res?;
+                    buf
+                }
+            };
+
+            buf[..len].copy_from_slice(&data);
+            active_writes.push(write_block_at(&dst, buf, len, offset));
+            offset += len as u64;
+        }
+
+        // wait for outstanding writes
+        while let Some((res, _)) = active_writes.next().await {
+            res?;
+        }
+        dst.sync_all().await?;
+
+        if offset != header.source_size {
+            warn!(
+                "restored {offset} bytes while source size is {}",
+                header.source_size
+            );
+        }
+
+        anyhow::Ok(())
+    })?;
+
+    if let Some((offset, data)) = tail {
+        debug!(
+            "writing {} bytes at offset {offset} without O_DIRECT",
+            data.len()
+        );
+        let dst = fs::OpenOptions::new().write(true).open(target)?;
+        dst.write_all_at(&data, offset)?;
+        dst.sync_all()?;
+    }
+
+    pbar.finish();
+
+    Ok(hashes
+        .digests()
+        .into_iter()
+        .map(|(algorithm, digest)| (algorithm.to_string(), digest))
+        .collect())
+}
+
+// write len bytes of a block and give back the buffer
+async fn write_block_at(
+    dst: &tokio_uring::fs::File,
+    buf: FixedBuf,
+    len: usize,
+    offset: u64,
+) -> (std::io::Result<()>, FixedBuf) {
+    let (res, slice) = dst.write_fixed_all_at(buf.slice(..len), offset).await;
+    (res, slice.into_inner())
+}
+
+// check target is big enough, or create it when it's a file
+fn prepare_target(target: &Path, header: &ImageHeader, force: bool) -> anyhow::Result<()> {
+    let metadata = fs::metadata(target).ok();
+    let is_block_device = metadata
+        .as_ref()
+        .is_some_and(|m| m.file_type().is_block_device());
+
+    if is_block_device {
+        let target_size = Device::size(target)?;
+        if target_size < header.source_size {
+            bail!(
+                "target {} is too small: {target_size} bytes while image source size is {}",
+                target.display(),
+                header.source_size
+            );
+        }
+        if target_size > header.source_size {
+            warn!(
+                "target {} is bigger than image source: {target_size} > {}",
+                target.display(),
+                header.source_size
+            );
+        }
+    } else {
+        if metadata.is_some() && !force {
+            bail!(
+                "target {} already exists, use --force to overwrite it",
+                target.display()
+            );
+        }
+        let file = File::create(target)
+            .with_context(|| format!("unable to create target {}", target.display()))?;
+        file.set_len(header.source_size)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        hash::{HashAlgorithm, Hashes},
+        test_util::{TempDir, random_bytes, write_image},
+        writer::WriterParams,
+    };
+
+    #[test]
+    fn unaligned_tail() -> anyhow::Result<()> {
+        let dir = TempDir::new("restore");
+        let (image, target) = (dir.join("image.img"), dir.join("restored.raw"));
+
+        // zero and incompressible blocks, the last one being short of a block
+        let mut data = random_bytes(10 * 4096 + 1000);
+        data[4096..3 * 4096].fill(0);
+        let params = WriterParams {
+            output_file: Some(image.clone()),
+            block_size: 4096,
+            source_size: data.len() as u64,
+            compress: true,
+            hashes: vec![HashAlgorithm::Sha256],
+            nb_workers: 4,
+            ..Default::default()
+        };
+        write_image(params, data.chunks(4096))?;
+
+        let restore_args = |force: &[&str]| {
+            let mut args = vec!["dimg", "--restore"];
+            args.extend(["--if", image.to_str().unwrap()]);
+            args.extend(["--of", target.to_str().unwrap()]);
+            args.extend(force);
+            Args::try_parse_from(args)
+        };
+        let digests = restore(&restore_args(&[])?)?;
+        assert_eq!(fs::read(&target)?, data);
+
+        let mut hashes = Hashes::new(&[HashAlgorithm::Sha256]);
+        hashes.update(&data);
+        assert_eq!(digests[0].1, hashes.digests()[0].1);
+
+        // the target is there now, and only overwritten when forced
+        let err = restore(&restore_args(&[])?).unwrap_err();
+        assert!(err.to_string().contains("--force"));
+        restore(&restore_args(&["--force"])?)?;
+        assert_eq!(fs::read(&target)?, data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+// segmented output: an image is split into name.001, name.002, ... files of a maximum size,
+// and read back as one logical image
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use log::debug;
+
+// name of the nth segment (starting at 1) of an image
+pub fn segment_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{n:03}"));
+    PathBuf::from(name)
+}
+
+// writes to a single file, or to segments when a segment size is set
+pub struct SegmentWriter {
+    // path given by user
+    path: PathBuf,
+
+    // maximum size of a segment
+    segment_size: Option<u64>,
+
+    // current segment number and how many bytes were written to it
+    segment: usize,
+    written: u64,
+
+    // offset of each segment in the logical output
+    starts: Vec<u64>,
+
+    // holes must be explicitly punched in block devices as they might hold data
+    is_block_device: bool,
+
+    // true if existing data is updated, skipped bytes being kept as they are
+    in_place: bool,
+
+    // handles reading back each segment, opened the first time a record is read from it
+    readers: Vec<Option<File>>,
+
+    writer: BufWriter<File>,
+}
+
+impl SegmentWriter {
+    pub fn create(path: &Path, segment_size: Option<u64>) -> anyhow::Result<Self> {
+        let first = match segment_size {
+            Some(_) => segment_path(path, 1),
+            None => path.to_path_buf(),
+        };
+        let file = File::create(&first)
+            .with_context(|| format!("unable to create output file {}", first.display()))?;
+        let is_block_device = file.metadata()?.file_type().is_block_device();
+
+        // files left by an earlier run would be read back as part of this image: segments of a
+        // longer run, or the single file of an unsegmented one which is preferred to segments
+        let stale: Vec<PathBuf> = match segment_size {
+            Some(_) => path
+                .is_file()
+                .then(|| path.to_path_buf())
+                .into_iter()
+                .chain(
+                    (2..)
+                        .map(|n| segment_path(path, n))
+                        .take_while(|p| p.exists()),
+                )
+                .collect(),
+            None => (1..)
+                .map(|n| segment_path(path, n))
+                .take_while(|p| p.exists())
+                .collect(),
+        };
+        for stale in stale {
+            debug!("removing stale image file {}", stale.display());
+            fs::remove_file(&stale)
+                .with_context(|| format!("unable to remove image file {}", stale.display()))?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            segment_size,
+            segment: 1,
+            written: 0,
+            starts: vec![0],
+            is_block_device,
+            in_place: false,
+            readers: Vec::new(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    // open an existing single file or device to overwrite some of its data
+    pub fn update(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("unable to open output file {}", path.display()))?;
+        let is_block_device = file.metadata()?.file_type().is_block_device();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            segment_size: None,
+            segment: 1,
+            written: 0,
+            starts: vec![0],
+            is_block_device,
+            in_place: true,
+            readers: Vec::new(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    // leave a hole of len bytes instead of writing zeros
+    pub fn skip(&mut self, mut len: u64) -> io::Result<()> {
+        while len > 0 {
+            let room = match self.segment_size {
+                Some(segment_size) => {
+                    if self.written >= segment_size {
+                        self.roll()?;
+                    }
+                    (segment_size - self.written).min(len)
+                }
+                None => len,
+            };
+
+            // seeking flushes any buffered data
+            let offset = self.writer.stream_position()?;
+            self.writer.seek(SeekFrom::Current(room as i64))?;
+
+            if self.is_block_device && !self.in_place {
+                punch_hole(self.writer.get_ref(), offset, room)?;
+            }
+
+            self.written += room;
+            len -= room;
+        }
+
+        Ok(())
+    }
+
+    // flush data and make sure a file ending with a hole has its full size
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        if !self.is_block_device {
+            self.writer.get_ref().set_len(self.written)?;
+        }
+
+        Ok(())
+    }
+
+    // read back data written at this offset of the logical output, within a single segment
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let n = self.starts.partition_point(|start| *start <= offset);
+        let local = offset - self.starts[n - 1];
+
+        // only data still buffered for the current segment needs a flush
+        let on_disk = self.written - self.writer.buffer().len() as u64;
+        if n == self.segment && local + buf.len() as u64 > on_disk {
+            self.writer.flush()?;
+        }
+
+        // output files are opened write only
+        if self.readers.len() < n {
+            self.readers.resize_with(n, || None);
+        }
+        let reader = match &mut self.readers[n - 1] {
+            Some(reader) => reader,
+            reader => {
+                let path = match self.segment_size {
+                    Some(_) => segment_path(&self.path, n),
+                    None => self.path.clone(),
+                };
+                reader.insert(File::open(path)?)
+            }
+        };
+        reader.read_exact_at(buf, local)
+    }
+
+    // make sure a record of len bytes won't be split across 2 segments
+    pub fn start_record(&mut self, len: usize) -> io::Result<()> {
+        if let Some(segment_size) = self.segment_size
+            && self.written > 0
+            && self.written + len as u64 > segment_size
+        {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    // close current segment and open the next one
+    fn roll(&mut self) -> io::Result<()> {
+        self.finish()?;
+
+        self.segment += 1;
+        self.starts
+            .push(self.starts[self.segment - 2] + self.written);
+        self.written = 0;
+
+        let next = segment_path(&self.path, self.segment);
+        debug!("rolling output over to {}", next.display());
+        self.writer = BufWriter::new(File::create(next)?);
+
+        Ok(())
+    }
+}
+
+impl Write for SegmentWriter {
+    // never write more than the segment size into a segment
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = match self.segment_size {
+            Some(segment_size) => {
+                if self.written >= segment_size {
+                    self.roll()?;
+                }
+                (segment_size - self.written).min(buf.len() as u64) as usize
+            }
+            None => buf.len(),
+        };
+
+        let n = self.writer.write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// deallocate a range, which reads back as zeros
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// reads a single file or a set of segments as one logical image
+#[derive(Debug)]
+pub struct SegmentReader {
+    // segment files with their starting offset in the logical image
+    segments: Vec<(File, u64)>,
+
+    // total length of the image
+    len: u64,
+
+    // current position in the logical image
+    pos: u64,
+}
+
+impl SegmentReader {
+    // open path as is, or the segments name.001, name.002, ... if path is a segment
+    // or doesn't exist but its first segment does
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let first = if path.exists() && path.extension().is_some_and(|ext| ext == "001") {
+            Some(path.with_extension(""))
+        } else if !path.exists() && segment_path(path, 1).exists() {
+            Some(path.to_path_buf())
+        } else {
+            None
+        };
+
+        let paths = match first {
+            Some(base) => (1..)
+                .map(|n| segment_path(&base, n))
+                .take_while(|p| p.exists())
+                .collect(),
+            None => vec![path.to_path_buf()],
+        };
+        debug!("image files: {:?}", paths);
+
+        let mut segments = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in &paths {
+            let file = File::open(path)
+                .with_context(|| format!("unable to open image {}", path.display()))?;
+            let size = fs::metadata(path)?.len();
+
+            segments.push((file, len));
+            len += size;
+        }
+
+        if segments.is_empty() {
+            bail!("no segment found for image {}", path.display());
+        }
+
+        Ok(Self {
+            segments,
+            len,
+            pos: 0,
+        })
+    }
+
+    // length of the logical image
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // segment holding current position
+        let i = self
+            .segments
+            .partition_point(|(_, start)| *start <= self.pos)
+            - 1;
+        let (file, start) = &self.segments[i];
+        let end = self.segments.get(i + 1).map_or(self.len, |(_, s)| *s);
+
+        let max = (end - self.pos).min(buf.len() as u64) as usize;
+        let n = file.read_at(&mut buf[..max], self.pos - start)?;
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for SegmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+
+        match new {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let dir = TempDir::new("segment");
+        let path = dir.join("image.img");
+
+        // 3 records of 40 bytes, followed by a 70 bytes trailer
+        let mut writer = SegmentWriter::create(&path, Some(100))?;
+        let mut data = Vec::new();
+        for i in 0..3u8 {
+            let record = vec![i; 40];
+            writer.start_record(record.len())?;
+            writer.write_all(&record)?;
+            data.extend_from_slice(&record);
+        }
+
+        // records are read back from older segments, or while still buffered
+        let mut record = [0u8; 40];
+        writer.read_at(&mut record, 0)?;
+        assert_eq!(record, [0; 40]);
+        writer.read_at(&mut record, 80)?;
+        assert_eq!(record, [2; 40]);
+
+        writer.write_all(&[0xFF; 70])?;
+        writer.flush()?;
+        data.extend_from_slice(&[0xFF; 70]);
+
+        // records are not split, trailer is
+        assert_eq!(fs::metadata(segment_path(&path, 1))?.len(), 80);
+        assert_eq!(fs::metadata(segment_path(&path, 2))?.len(), 100);
+        assert_eq!(fs::metadata(segment_path(&path, 3))?.len(), 10);
+        assert!(!segment_path(&path, 4).exists());
+
+        // read back from base name or first segment
+        for p in [path.clone(), segment_path(&path, 1)] {
+            let mut reader = SegmentReader::open(&p)?;
+            assert_eq!(reader.size(), data.len() as u64);
+
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read)?;
+            assert_eq!(read, data);
+
+            let mut tail = [0u8; 20];
+            reader.seek(SeekFrom::End(-80))?;
+            reader.read_exact(&mut tail)?;
+            assert_eq!(&tail[..10], &[2; 10]);
+            assert_eq!(&tail[10..], &[0xFF; 10]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stale_segments() -> anyhow::Result<()> {
+        let dir = TempDir::new("stale");
+        let path = dir.join("image.img");
+
+        // a first run spreading over 5 segments
+        let mut writer = SegmentWriter::create(&path, Some(100))?;
+        writer.write_all(&[1; 450])?;
+        writer.finish()?;
+        assert!(segment_path(&path, 5).exists());
+
+        // imaging again a shorter source only leaves its own segments
+        let mut writer = SegmentWriter::create(&path, Some(100))?;
+        writer.write_all(&[2; 150])?;
+        writer.finish()?;
+        assert!(!segment_path(&path, 3).exists());
+
+        let mut read = Vec::new();
+        SegmentReader::open(&path)?.read_to_end(&mut read)?;
+        assert_eq!(read, [2; 150]);
+
+        // a single file replaces segments, and the other way round
+        let mut writer = SegmentWriter::create(&path, None)?;
+        writer.write_all(&[3; 150])?;
+        writer.finish()?;
+        assert!(!segment_path(&path, 1).exists());
+        let mut read = Vec::new();
+        SegmentReader::open(&path)?.read_to_end(&mut read)?;
+        assert_eq!(read, [3; 150]);
+
+        let mut writer = SegmentWriter::create(&path, Some(100))?;
+        writer.write_all(&[4; 150])?;
+        writer.finish()?;
+        assert!(!path.exists());
+        let mut read = Vec::new();
+        SegmentReader::open(&path)?.read_to_end(&mut read)?;
+        assert_eq!(read, [4; 150]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sparse() -> anyhow::Result<()> {
+        let dir = TempDir::new("sparse");
+        let path = dir.join("image.raw");
+
+        let mut writer = SegmentWriter::create(&path, None)?;
+        writer.write_all(&[1; 10])?;
+        writer.skip(1 << 20)?;
+        writer.write_all(&[2; 10])?;
+        writer.skip(1 << 20)?;
+        writer.finish()?;
+
+        let data = fs::read(&path)?;
+        assert_eq!(data.len(), 20 + 2 * (1 << 20));
+        assert_eq!(&data[..10], &[1; 10]);
+        assert!(data[10..10 + (1 << 20)].iter().all(|b| *b == 0));
+        assert_eq!(&data[10 + (1 << 20)..20 + (1 << 20)], &[2; 10]);
+        assert!(data[20 + (1 << 20)..].iter().all(|b| *b == 0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+// compressed dd output: raw data goes through a standard stream compressor, so the output
+// (or the concatenation of its segments) can be read by stock lz4, zstd or gzip tools
+use std::io::{self, Read, Write};
+
+use flate2::{Compression, write::GzEncoder};
+
+use crate::{compression::Algorithm, segment::SegmentWriter};
+
+pub enum StreamEncoder {
+    Lz4(lz4::Encoder<SegmentWriter>),
+    Zstd(zstd::Encoder<'static, SegmentWriter>),
+    Gzip(GzEncoder<SegmentWriter>),
+}
+
+impl StreamEncoder {
+    // start a stream, using the algorithm default level if none is given
+    pub fn new(
+        writer: SegmentWriter,
+        algorithm: Algorithm,
+        level: Option<i32>,
+    ) -> anyhow::Result<Self> {
+        let encoder = match algorithm {
+            Algorithm::Lz4 => Self::Lz4(
+                lz4::EncoderBuilder::new()
+                    .level(level.unwrap_or_default() as u32)
+                    .build(writer)?,
+            ),
+            Algorithm::Zstd => Self::Zstd(zstd::Encoder::new(
+                writer,
+                level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+            )?),
+            Algorithm::Gzip => Self::Gzip(GzEncoder::new(
+                writer,
+                level.map_or(Compression::default(), |l| Compression::new(l as u32)),
+            )),
+        };
+
+        Ok(encoder)
+    }
+
+    // write the end of the stream and give back the underlying writer
+    pub fn finish(self) -> io::Result<SegmentWriter> {
+        match self {
+            Self::Lz4(encoder) => {
+                let (writer, result) = encoder.finish();
+                result.map(|_| writer)
+            }
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for StreamEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Lz4(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Lz4(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// where chunks end up: segments as is, or a compressed stream over segments in dd mode
+pub enum Output {
+    Plain(SegmentWriter),
+    Stream(StreamEncoder),
+}
+
+impl Output {
+    // records are only kept whole in plain output
+    pub fn start_record(&mut self, len: usize) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.start_record(len),
+            Self::Stream(_) => Ok(()),
+        }
+    }
+
+    // a compressed stream can't have holes, zeros are compressed instead
+    pub fn skip(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.skip(len),
+            Self::Stream(encoder) => {
+                io::copy(&mut io::repeat(0).take(len), encoder)?;
+                Ok(())
+            }
+        }
+    }
+
+    // read back what was written, which can't be done in a compressed stream
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.read_at(buf, offset),
+            Self::Stream(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed stream can't be read back",
+            )),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.finish(),
+            Self::Stream(encoder) => encoder.finish()?.finish(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Stream(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Stream(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let dir = TempDir::new("stream");
+        let data = b"hello world".repeat(10000);
+
+        for algorithm in [Algorithm::Lz4, Algorithm::Zstd, Algorithm::Gzip] {
+            let path = dir.join(format!("image.raw.{algorithm}"));
+
+            let writer = SegmentWriter::create(&path, None)?;
+            let mut output = Output::Stream(StreamEncoder::new(writer, algorithm, None)?);
+            output.write_all(&data[..50000])?;
+            output.skip(100)?;
+            output.write_all(&data[50100..])?;
+            output.finish()?;
+
+            let file = fs::File::open(&path)?;
+            let mut decoded = Vec::new();
+            match algorithm {
+                Algorithm::Lz4 => lz4::Decoder::new(file)?.read_to_end(&mut decoded)?,
+                Algorithm::Zstd => zstd::Decoder::new(file)?.read_to_end(&mut decoded)?,
+                Algorithm::Gzip => GzDecoder::new(file).read_to_end(&mut decoded)?,
+            };
+
+            let mut expected = data.clone();
+            expected[50000..50100].fill(0);
+            assert!(fs::metadata(&path)?.len() < data.len() as u64 / 10);
+            assert_eq!(decoded, expected);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+// helpers shared by tests: pseudo random data, temporary directories and image writing
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+};
+
+use crate::writer::{WriterParams, WriterSummary, writer_thread};
+
+// tells apart directories of tests running at the same time
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+// pseudo random bytes from a xorshift generator, always the same for a given length, which
+// neither compress nor deduplicate
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut x = 0x2545F4914F6CDD1Du64;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+// directory unique to a test, removed with everything in it once dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("dimg-{name}-{}-{n}", std::process::id()));
+        fs::create_dir_all(&path).expect("unable to create test directory");
+        Self(path)
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// write blocks to the image params point to, as an acquisition does. Blocks are sent last
+// first so the writer has to put them back in order
+pub fn write_image<'a>(
+    params: WriterParams,
+    blocks: impl IntoIterator<Item = &'a [u8]>,
+) -> anyhow::Result<WriterSummary> {
+    let blocks: Vec<_> = blocks.into_iter().collect();
+
+    let (tx, rx) = mpsc::channel();
+    let handle = writer_thread(rx, params);
+    for (i, block) in blocks.into_iter().enumerate().rev() {
+        // the writer stops at its first error, given back once joined
+        if tx.send((i as u64, block.to_vec())).is_err() {
+            break;
+        }
+    }
+    drop(tx);
+    handle.join().unwrap()
+}
@@ -0,0 +1,178 @@
+// acquisition trailer: outcome of the acquisition stored at the end of the image, so the image
+// itself proves whether it was completed and what its digests were
+//
+// layout (big-endian):
+//
+// completed (1) | bytes read (8) | start time (8) | end time (8) | errors (8) | count (1)
+// | (algorithm length (1) | algorithm | digest length (1) | digest) * count
+use std::{
+    io::{Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use humantime::format_rfc3339_seconds;
+use serde::{Serialize, Serializer, ser::SerializeMap};
+
+// length of the fixed part of the trailer, before digests
+const FIXED_LEN: usize = 1 + 8 + 8 + 8 + 8 + 1;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Trailer {
+    // true if the whole source was read without error
+    pub completed: bool,
+
+    // number of bytes read from the source and written to the image
+    pub bytes_read: u64,
+
+    // acquisition start and end, as seconds since UNIX epoch
+    pub start_time: u64,
+    pub end_time: u64,
+
+    // number of read errors
+    pub errors: u64,
+
+    // all digests computed as (algorithm, hex digest)
+    #[serde(serialize_with = "serialize_digests")]
+    pub digests: Vec<(String, String)>,
+}
+
+impl Trailer {
+    // digest recorded for this algorithm
+    pub fn digest(&self, algorithm: &str) -> Option<&str> {
+        self.digests
+            .iter()
+            .find(|(a, _)| a == algorithm)
+            .map(|(_, d)| d.as_str())
+    }
+
+    // write trailer section, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        let count = u8::try_from(self.digests.len()).map_err(|_| anyhow!("too many digests"))?;
+
+        dst.write_all(&[self.completed as u8])?;
+        dst.write_all(&self.bytes_read.to_be_bytes())?;
+        dst.write_all(&self.start_time.to_be_bytes())?;
+        dst.write_all(&self.end_time.to_be_bytes())?;
+        dst.write_all(&self.errors.to_be_bytes())?;
+        dst.write_all(&[count])?;
+
+        let mut written = FIXED_LEN;
+        for (algorithm, digest) in &self.digests {
+            for s in [algorithm, digest] {
+                let len = u8::try_from(s.len()).map_err(|_| anyhow!("'{s}' is too long"))?;
+                dst.write_all(&[len])?;
+                dst.write_all(s.as_bytes())?;
+                written += 1 + s.len();
+            }
+        }
+
+        Ok(written)
+    }
+
+    // read a trailer section of len bytes
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        let mut buf = vec![0u8; len as usize];
+        src.read_exact(&mut buf)?;
+
+        if buf.len() < FIXED_LEN {
+            bail!("corrupted trailer: only {} bytes", buf.len());
+        }
+
+        let mut trailer = Self {
+            completed: buf[0] != 0,
+            bytes_read: u64::from_be_bytes(buf[1..9].try_into()?),
+            start_time: u64::from_be_bytes(buf[9..17].try_into()?),
+            end_time: u64::from_be_bytes(buf[17..25].try_into()?),
+            errors: u64::from_be_bytes(buf[25..33].try_into()?),
+            digests: Vec::new(),
+        };
+
+        let count = buf[33];
+        let mut rest = &buf[FIXED_LEN..];
+        for _ in 0..count {
+            let algorithm = read_string(&mut rest)?;
+            let digest = read_string(&mut rest)?;
+            trailer.digests.push((algorithm, digest));
+        }
+
+        if !rest.is_empty() {
+            bail!("corrupted trailer: {} trailing bytes", rest.len());
+        }
+
+        Ok(trailer)
+    }
+}
+
+// seconds since UNIX epoch for a time
+pub fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// time given as seconds since UNIX epoch, in RFC 3339 format
+pub fn rfc3339(secs: u64) -> String {
+    format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+// digests serialized as a JSON object, keeping order
+fn serialize_digests<S: Serializer>(
+    digests: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(digests.len()))?;
+    for (algorithm, digest) in digests {
+        map.serialize_entry(algorithm, digest)?;
+    }
+    map.end()
+}
+
+// length prefixed string
+fn read_string(src: &mut &[u8]) -> anyhow::Result<String> {
+    let (len, rest) = src
+        .split_first()
+        .ok_or_else(|| anyhow!("corrupted trailer: truncated digest"))?;
+
+    if rest.len() < *len as usize {
+        bail!("corrupted trailer: truncated digest");
+    }
+
+    let (s, rest) = rest.split_at(*len as usize);
+    *src = rest;
+    Ok(String::from_utf8(s.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let trailer = Trailer {
+            completed: true,
+            bytes_read: 2310720,
+            start_time: 1792224000,
+            end_time: 1792224042,
+            errors: 0,
+            digests: vec![
+                ("sha256".to_string(), "6c7dba28".to_string()),
+                ("blake3".to_string(), "fd6b6eee".to_string()),
+            ],
+        };
+
+        let mut buf = Vec::new();
+        let n = trailer.write(&mut buf)?;
+        assert_eq!(n, buf.len());
+
+        let read = Trailer::read(&mut buf.as_slice(), n as u64)?;
+        assert_eq!(read, trailer);
+        assert_eq!(read.digest("blake3"), Some("fd6b6eee"));
+
+        // truncated section
+        buf.pop();
+        assert!(Trailer::read(&mut buf.as_slice(), n as u64 - 1).is_err());
+
+        Ok(())
+    }
+}
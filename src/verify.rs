@@ -0,0 +1,384 @@
+// verify module: decode an image and compare its content digests with expected ones
+
+use std::io::{Seek, SeekFrom};
+
+use anyhow::{Context, anyhow, bail};
+use indicatif::ProgressBar;
+use log::{debug, warn};
+
+use crate::{
+    args::Args,
+    hash::{HashAlgorithm, HashPool, PoolOutput, hex},
+    image_reader::ImageReader,
+    info::{read_block_hashes, read_hash_windows, read_index, read_merkle, read_trailer},
+    merkle::{GROUP_LEAVES, MerkleBuilder, MerkleTree},
+    repository::Repository,
+    segment::SegmentReader,
+};
+
+// an expected digest for an algorithm
+#[derive(Debug, PartialEq)]
+pub struct Expected {
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+}
+
+impl TryFrom<&str> for Expected {
+    type Error = anyhow::Error;
+
+    // parse ALGO:DIGEST
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (algorithm, digest) = value
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected digest '{value}' is not formatted as ALGO:DIGEST"))?;
+
+        let Some(algorithm) = HashAlgorithm::from_name(&algorithm.to_lowercase()) else {
+            let supported: Vec<_> = HashAlgorithm::ALL.iter().map(|a| a.name()).collect();
+            bail!(
+                "unknown hash algorithm '{algorithm}', supported: {}",
+                supported.join(", ")
+            );
+        };
+
+        Ok(Self {
+            algorithm,
+            digest: digest.trim().to_lowercase(),
+        })
+    }
+}
+
+// decode the image given by --if, re-hash its content and compare with expected digests,
+// either given by --expect or recorded in the image trailer
+// returns an error if any digest doesn't match
+pub fn verify(args: &Args) -> anyhow::Result<()> {
+    if let Some(block) = args.block {
+        return verify_block(args, block);
+    }
+
+    let mut expected = args
+        .expect
+        .iter()
+        .map(|e| Expected::try_from(e.as_str()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut image = SegmentReader::open(&args.r#if)?;
+
+    // digests stored at acquisition time, unless overridden on the command line
+    match read_trailer(&mut image) {
+        Ok(Some(trailer)) => {
+            debug!("trailer: {:?}", trailer);
+            if !trailer.completed {
+                warn!(
+                    "acquisition was not completed: {} bytes read, {} errors",
+                    trailer.bytes_read, trailer.errors
+                );
+            }
+
+            for algorithm in HashAlgorithm::ALL {
+                if let Some(digest) = trailer.digest(algorithm.name())
+                    && !expected.iter().any(|e| e.algorithm == algorithm)
+                {
+                    expected.push(Expected {
+                        algorithm,
+                        digest: digest.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(None) => debug!("no trailer in image"),
+        Err(e) => warn!("unable to read trailer: {e}"),
+    }
+    debug!("expected digests: {:?}", expected);
+
+    // windows are compared too, to tell where the content differs
+    let windows = match read_hash_windows(&mut image) {
+        Ok(windows) => windows,
+        Err(e) => {
+            warn!("unable to read hash windows: {e}");
+            None
+        }
+    };
+    let window_algorithms = windows.as_ref().and_then(|w| {
+        w.algorithms
+            .iter()
+            .map(|a| HashAlgorithm::from_name(a))
+            .collect::<Option<Vec<_>>>()
+    });
+
+    // the tree is rebuilt to check its root, and which blocks differ
+    let tree = read_tree(&mut image);
+
+    // per-block digests tell the first block which differs
+    let block_hashes = match read_block_hashes(&mut image) {
+        Ok(block_hashes) => block_hashes,
+        Err(e) => {
+            warn!("unable to read block hashes: {e}");
+            None
+        }
+    };
+    let mut first_mismatch = None;
+
+    image.seek(SeekFrom::Start(0))?;
+    let mut decoder = ImageReader::open(image)?;
+    if let Some(repo) = &args.repo {
+        decoder.set_repository(Repository::open(repo)?);
+    }
+    if let Some(parent) = &args.parent {
+        decoder.set_parent(parent)?;
+    }
+    let header = decoder.header().clone();
+    debug!("header: {:?}", header);
+
+    // calculate hashes used at acquisition time and those we're asked to compare with
+    let mut algorithms = args.hash_algorithms();
+    algorithms.extend(&header.hashes);
+    algorithms.extend(expected.iter().map(|e| e.algorithm));
+    algorithms.sort();
+    algorithms.dedup();
+    let mut hashes = HashPool::new(&algorithms);
+    if let (Some(w), Some(algorithms)) = (&windows, &window_algorithms) {
+        hashes = hashes.with_windows(w.size, algorithms);
+    }
+    if let Some(t) = &tree {
+        hashes = hashes.with_merkle(t.leaf_size);
+    }
+
+    let pbar = ProgressBar::new(header.source_size);
+    let mut logical_size = 0u64;
+
+    while let Some(block) = decoder.next_block()? {
+        hashes.update(&block.data);
+        if let Some(expected) = &block_hashes
+            && first_mismatch.is_none()
+            && expected.get(block.number) != Some(&expected.algorithm.digest(&block.data))
+        {
+            first_mismatch = Some(block.number);
+        }
+        logical_size += block.data.len() as u64;
+        pbar.inc(block.data.len() as u64);
+    }
+    pbar.finish_and_clear();
+
+    let mut failed = false;
+
+    if logical_size != header.source_size {
+        println!(
+            "size: {logical_size} bytes MISMATCH (source size: {})",
+            header.source_size
+        );
+        failed = true;
+    }
+
+    // compare what we've computed with what's expected
+    let PoolOutput {
+        digests,
+        windows: computed_windows,
+        merkle,
+    } = hashes.finish();
+    for (algorithm, digest) in &digests {
+        match expected.iter().find(|e| e.algorithm.name() == *algorithm) {
+            Some(e) if e.digest == *digest => println!("{algorithm}: {digest} OK"),
+            Some(e) => {
+                println!("{algorithm}: {digest} MISMATCH (expected: {})", e.digest);
+                failed = true;
+            }
+            None => println!("{algorithm}: {digest}"),
+        }
+    }
+
+    // differing windows locate the damage
+    if let (Some(expected), Some(computed)) = (&windows, computed_windows) {
+        let mismatches = computed.mismatches(expected);
+        for window in &mismatches {
+            println!("window: {}-{} MISMATCH", window.start, window.end - 1);
+        }
+        if mismatches.is_empty() {
+            println!("windows: {} OK", expected.windows.len());
+        }
+        failed |= !mismatches.is_empty();
+    }
+
+    // the fingerprint, and the first group of blocks which doesn't give it
+    if let (Some(expected), Some(computed)) = (&tree, merkle) {
+        match expected.first_mismatch(&computed) {
+            None => println!("merkle: {} OK", hex(&computed.root)),
+            Some((first, last)) => {
+                println!(
+                    "merkle: {} MISMATCH (expected: {}, first difference in blocks {first}-{last})",
+                    hex(&computed.root),
+                    hex(&expected.root)
+                );
+                failed = true;
+            }
+        }
+    }
+
+    // the first block which doesn't give its stored digest locates the damage
+    if let Some(expected) = &block_hashes {
+        match first_mismatch {
+            None => println!("blocks: {} OK", expected.len()),
+            Some(block) => {
+                println!("block: {block} MISMATCH (first block which differs)");
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        match first_mismatch {
+            Some(block) => bail!(
+                "verification of {} failed, first differing block is {block}",
+                args.r#if.display()
+            ),
+            None => bail!("verification of {} failed", args.r#if.display()),
+        }
+    }
+    if expected.is_empty() && tree.is_none() && block_hashes.is_none() {
+        bail!(
+            "no expected digest to verify {} against, content was only decoded",
+            args.r#if.display()
+        );
+    }
+
+    Ok(())
+}
+
+// check a single block against the Merkle root, only decoding the blocks of its stored node
+fn verify_block(args: &Args, block: u64) -> anyhow::Result<()> {
+    let mut image = SegmentReader::open(&args.r#if)?;
+    let tree = read_tree(&mut image).with_context(|| {
+        format!(
+            "{} has no Merkle tree, it must be acquired with --merkle",
+            args.r#if.display()
+        )
+    })?;
+    if block >= tree.leaves {
+        bail!(
+            "block {block} is beyond the {} blocks of the image",
+            tree.leaves
+        );
+    }
+
+    // source bytes of the group of blocks holding this one
+    let group = block / GROUP_LEAVES;
+    let (first, last) = (
+        group * GROUP_LEAVES,
+        ((group + 1) * GROUP_LEAVES).min(tree.leaves) - 1,
+    );
+    let (start, end) = (first * tree.leaf_size, (last + 1) * tree.leaf_size);
+
+    let index = read_index(&mut image)?;
+    let entry = *index
+        .locate(start)
+        .with_context(|| format!("block {block} is not in the index"))?;
+
+    image.seek(SeekFrom::Start(0))?;
+    let mut decoder = ImageReader::open(image)?;
+    if let Some(repo) = &args.repo {
+        decoder.set_repository(Repository::open(repo)?);
+    }
+    decoder.seek_entry(&entry)?;
+
+    // chunks might not be aligned on blocks, so only bytes of the group are hashed
+    let mut merkle = MerkleBuilder::new(tree.leaf_size);
+    let mut pos = entry.start;
+    while pos < end {
+        let Some(decoded) = decoder.next_block()? else {
+            break;
+        };
+        let len = decoded.data.len() as u64;
+        let (from, to) = (start.max(pos), end.min(pos + len));
+        if from < to {
+            merkle.update(&decoded.data[(from - pos) as usize..(to - pos) as usize]);
+        }
+        pos += len;
+    }
+
+    let node = merkle.finish().root;
+    if node != tree.nodes[group as usize] {
+        println!("block {block}: MISMATCH (blocks {first}-{last} don't give the Merkle root)");
+        bail!(
+            "verification of block {block} of {} failed",
+            args.r#if.display()
+        );
+    }
+    println!(
+        "block {block}: OK (blocks {first}-{last}, merkle: {})",
+        hex(&tree.root)
+    );
+
+    Ok(())
+}
+
+// Merkle tree of the image if any
+fn read_tree(image: &mut SegmentReader) -> Option<MerkleTree> {
+    match read_merkle(image) {
+        Ok(tree) => tree,
+        Err(e) => {
+            warn!("unable to read Merkle tree: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        cbt::BlockHash,
+        test_util::{TempDir, random_bytes, write_image},
+        writer::WriterParams,
+    };
+
+    #[test]
+    fn expected() -> anyhow::Result<()> {
+        let e = Expected::try_from("SHA256:ABCDEF")?;
+        assert_eq!(e.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(Expected::try_from("md5:00")?.algorithm, HashAlgorithm::Md5);
+        assert_eq!(e.digest, "abcdef");
+
+        assert!(Expected::try_from("abcdef").is_err());
+        assert!(Expected::try_from("crc32:abcdef").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn damaged() -> anyhow::Result<()> {
+        let dir = TempDir::new("verify");
+        let path = dir.join("image.img");
+
+        // incompressible blocks, stored as they are
+        let data = random_bytes(16 * 4096);
+        let params = WriterParams {
+            output_file: Some(path.clone()),
+            block_size: 4096,
+            source_size: data.len() as u64,
+            hashes: vec![HashAlgorithm::Sha256],
+            block_hash: Some(BlockHash::Blake3),
+            store_block_hashes: true,
+            nb_workers: 4,
+            ..Default::default()
+        };
+        write_image(params, data.chunks(4096))?;
+
+        let args = Args::try_parse_from(["dimg", "--verify", "--if", path.to_str().unwrap()])?;
+        verify(&args)?;
+
+        // damage a byte in the middle of block 5
+        let mut image = fs::read(&path)?;
+        let block = &data[5 * 4096..6 * 4096];
+        let at = image.windows(64).position(|w| w == &block[..64]).unwrap();
+        image[at + 2000] ^= 1;
+        fs::write(&path, &image)?;
+
+        let err = verify(&args).unwrap_err();
+        assert!(err.to_string().contains("first differing block is 5"));
+
+        Ok(())
+    }
+}
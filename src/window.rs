@@ -0,0 +1,264 @@
+// piecewise hashing: digests of every window of the ordered source stream, so a damaged copy
+// can be narrowed down to the windows which differ instead of the whole source
+//
+// section layout (big-endian):
+//
+// window size (8) | algorithm count (1) | (algorithm length (1) | algorithm) * algorithms
+// | window count (8) | (start (8) | end (8) | (digest length (1) | digest) * algorithms) * count
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, anyhow, bail};
+use serde::Serialize;
+
+use crate::hash::{HashAlgorithm, Hashes};
+
+// digests of the source bytes from start to end (excluded)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Window {
+    pub start: u64,
+    pub end: u64,
+    pub digests: Vec<String>,
+}
+
+// all windows of a source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HashWindows {
+    pub size: u64,
+    pub algorithms: Vec<String>,
+    pub windows: Vec<Window>,
+}
+
+impl HashWindows {
+    // write section, returning the number of bytes written
+    pub fn write<W: Write>(&self, dst: &mut W) -> anyhow::Result<usize> {
+        let count =
+            u8::try_from(self.algorithms.len()).map_err(|_| anyhow!("too many algorithms"))?;
+
+        dst.write_all(&self.size.to_be_bytes())?;
+        dst.write_all(&[count])?;
+        let mut written = 8 + 1;
+        for algorithm in &self.algorithms {
+            written += write_string(dst, algorithm)?;
+        }
+
+        dst.write_all(&(self.windows.len() as u64).to_be_bytes())?;
+        written += 8;
+        for window in &self.windows {
+            dst.write_all(&window.start.to_be_bytes())?;
+            dst.write_all(&window.end.to_be_bytes())?;
+            written += 16;
+            for digest in &window.digests {
+                written += write_string(dst, digest)?;
+            }
+        }
+
+        Ok(written)
+    }
+
+    // read a section of len bytes
+    pub fn read<R: Read>(src: &mut R, len: u64) -> anyhow::Result<Self> {
+        let mut buf = vec![0u8; len as usize];
+        src.read_exact(&mut buf)?;
+        let mut rest = buf.as_slice();
+
+        let size = read_u64(&mut rest)?;
+        let count = *rest.first().context("corrupted hash windows: truncated")?;
+        rest = &rest[1..];
+        let algorithms = (0..count)
+            .map(|_| read_string(&mut rest))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let count = read_u64(&mut rest)?;
+        let mut windows = Vec::new();
+        for _ in 0..count {
+            windows.push(Window {
+                start: read_u64(&mut rest)?,
+                end: read_u64(&mut rest)?,
+                digests: algorithms
+                    .iter()
+                    .map(|_| read_string(&mut rest))
+                    .collect::<anyhow::Result<_>>()?,
+            });
+        }
+
+        if !rest.is_empty() {
+            bail!("corrupted hash windows: {} trailing bytes", rest.len());
+        }
+
+        Ok(Self {
+            size,
+            algorithms,
+            windows,
+        })
+    }
+
+    // write windows as text, one line per window and algorithm
+    pub fn write_log(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("unable to create hash log {}", path.display()))?;
+        let mut dst = BufWriter::new(file);
+
+        for window in &self.windows {
+            for (algorithm, digest) in self.algorithms.iter().zip(&window.digests) {
+                writeln!(
+                    dst,
+                    "{}-{} {algorithm}:{digest}",
+                    window.start,
+                    window.end - 1
+                )?;
+            }
+        }
+        dst.flush()?;
+
+        Ok(())
+    }
+
+    // windows of other which don't have the same digests as ours
+    pub fn mismatches<'a>(&self, other: &'a HashWindows) -> Vec<&'a Window> {
+        other
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(i, w)| self.windows.get(*i) != Some(w))
+            .map(|(_, w)| w)
+            .collect()
+    }
+}
+
+// hashes the ordered stream, starting a new window every size bytes
+pub struct WindowHasher {
+    algorithms: Vec<HashAlgorithm>,
+    hashes: Hashes,
+
+    // offset of the current window and bytes hashed in it
+    start: u64,
+    len: u64,
+
+    windows: HashWindows,
+}
+
+impl WindowHasher {
+    pub fn new(size: u64, algorithms: &[HashAlgorithm]) -> Self {
+        Self {
+            algorithms: algorithms.to_vec(),
+            hashes: Hashes::new(algorithms),
+            start: 0,
+            len: 0,
+            windows: HashWindows {
+                size,
+                algorithms: algorithms.iter().map(|a| a.to_string()).collect(),
+                windows: Vec::new(),
+            },
+        }
+    }
+
+    // feed next data of the stream, which can span several windows
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (self.windows.size - self.len).min(data.len() as u64) as usize;
+            self.hashes.update(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+
+            if self.len == self.windows.size {
+                self.close();
+            }
+        }
+    }
+
+    // close the last window, which might be shorter
+    pub fn finish(mut self) -> HashWindows {
+        if self.len > 0 {
+            self.close();
+        }
+        self.windows
+    }
+
+    fn close(&mut self) {
+        let hashes = std::mem::replace(&mut self.hashes, Hashes::new(&self.algorithms));
+        self.windows.windows.push(Window {
+            start: self.start,
+            end: self.start + self.len,
+            digests: hashes.digests().into_iter().map(|(_, d)| d).collect(),
+        });
+
+        self.start += self.len;
+        self.len = 0;
+    }
+}
+
+fn write_string<W: Write>(dst: &mut W, s: &str) -> anyhow::Result<usize> {
+    let len = u8::try_from(s.len()).map_err(|_| anyhow!("'{s}' is too long"))?;
+    dst.write_all(&[len])?;
+    dst.write_all(s.as_bytes())?;
+    Ok(1 + s.len())
+}
+
+fn read_string(buf: &mut &[u8]) -> anyhow::Result<String> {
+    let len = *buf.first().context("corrupted hash windows: truncated")? as usize;
+    if buf.len() < 1 + len {
+        bail!("corrupted hash windows: truncated");
+    }
+    let s = String::from_utf8(buf[1..1 + len].to_vec())?;
+    *buf = &buf[1 + len..];
+    Ok(s)
+}
+
+fn read_u64(buf: &mut &[u8]) -> anyhow::Result<u64> {
+    if buf.len() < 8 {
+        bail!("corrupted hash windows: truncated");
+    }
+    let n = u64::from_be_bytes(buf[..8].try_into()?);
+    *buf = &buf[8..];
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() -> anyhow::Result<()> {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let algorithms = [HashAlgorithm::Md5, HashAlgorithm::Sha256];
+
+        // windows don't depend on how the stream is given
+        let hash = |piece: usize| {
+            let mut hasher = WindowHasher::new(4096, &algorithms);
+            data.chunks(piece).for_each(|p| hasher.update(p));
+            hasher.finish()
+        };
+        let windows = hash(1000);
+        assert_eq!(hash(4096), windows);
+        assert_eq!(hash(data.len()), windows);
+
+        let bounds: Vec<_> = windows.windows.iter().map(|w| (w.start, w.end)).collect();
+        assert_eq!(bounds, [(0, 4096), (4096, 8192), (8192, 10_000)]);
+
+        let mut hashes = Hashes::new(&algorithms);
+        hashes.update(&data[4096..8192]);
+        let digests: Vec<_> = hashes.digests().into_iter().map(|(_, d)| d).collect();
+        assert_eq!(windows.windows[1].digests, digests);
+
+        let mut buf = Vec::new();
+        let n = windows.write(&mut buf)?;
+        assert_eq!(n, buf.len());
+        assert_eq!(HashWindows::read(&mut buf.as_slice(), n as u64)?, windows);
+
+        // only the window holding a changed byte differs
+        let mut damaged = data.clone();
+        damaged[5000] ^= 1;
+        let mut hasher = WindowHasher::new(4096, &algorithms);
+        hasher.update(&damaged);
+        let damaged = hasher.finish();
+        let mismatches = windows.mismatches(&damaged);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].start, 4096);
+
+        Ok(())
+    }
+}
@@ -1,18 +1,34 @@
-// writer/hasher module
+// writer module: transform workers build chunks in parallel, a serializer hashes and
+// writes them in block order. With content-defined chunking, a chunker cuts the ordered
+// blocks into chunks before they go to transform workers
 
 use std::{
     collections::BTreeMap,
-    fs::File,
-    io::{BufWriter, Write},
     path::PathBuf,
-    sync::mpsc::Receiver,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
     thread::{self, JoinHandle},
+    time::SystemTime,
 };
 
-use log::{debug, trace};
-use sha2::{Digest, Sha256};
+use anyhow::Context;
+use log::{debug, trace, warn};
 
-use crate::{args::Args, chunk::Chunk};
+use crate::{
+    args::Args,
+    cbt::{BlockHash, BlockHashes, add_change},
+    cdc::Chunker,
+    chunk::Chunk,
+    compression::{Algorithm, Dictionary},
+    hash::{HashAlgorithm, HashPool, PoolOutput, hex},
+    image_writer::{DEDUP_ENTRY_LEN, ImageWriter},
+    metadata::Metadata,
+    parent::Parent,
+    trailer::{Trailer, epoch_secs},
+};
 
 // what is given to the writer thread to process incoming data blocks
 #[derive(Debug, Default)]
@@ -20,105 +36,653 @@ pub struct WriterParams {
     // true if user wants dd-like copy
     pub dd: bool,
 
-    // true id user wants data to be compressed using LZ4
+    // true id user wants data to be compressed
     pub compress: bool,
 
-    // true if user wants to calculate sha256 sum
-    pub sha256: bool,
+    // compression algorithm and level, default one for the algorithm if None
+    pub algorithm: Algorithm,
+    pub level: Option<i32>,
+
+    // zstd dictionary trained on the source, if any
+    pub dictionary: Option<Arc<Dictionary>>,
+
+    // true if blocks already written are replaced by a reference to them
+    pub dedup: bool,
+
+    // true if blocks are compared byte per byte before being deduplicated
+    pub dedup_verify: bool,
+
+    // maximum number of distinct blocks remembered for deduplication, no limit if None
+    pub dedup_entries: Option<usize>,
+
+    // true if blocks are cut into content-defined chunks, averaging block size
+    pub cdc: bool,
+
+    // if set, data chunks are kept in this repository and the output is a manifest
+    pub repository: Option<PathBuf>,
+
+    // if set, image is an increment of this one and only stores blocks which changed
+    pub parent: Option<Arc<Parent>>,
+
+    // algorithm of block digests, if they're needed by a sidecar, the image or the parent
+    pub block_hash: Option<BlockHash>,
 
-    // true if user wants to calculate blake3 sum
-    pub blake3: bool,
+    // true if block digests are stored in the image, which must be blake3 ones
+    pub store_block_hashes: bool,
+
+    // sidecar of block digests to write
+    pub cbt: Option<PathBuf>,
+
+    // block digests of a previous acquisition, to find which blocks changed since then
+    pub changed_since: Option<Arc<BlockHashes>>,
+
+    // true if unchanged blocks are skipped, output being updated in place
+    pub changed_only: bool,
+
+    // compressed data is kept only if it's at least this percentage smaller
+    pub min_saving: u8,
+
+    // digests of the source the user wants to calculate
+    pub hashes: Vec<HashAlgorithm>,
+
+    // if set, digests are also calculated for every window of this size
+    pub hash_window: Option<u64>,
+
+    // text file to write window digests to
+    pub hash_log: Option<PathBuf>,
+
+    // true if a Merkle tree is built over blocks
+    pub merkle: bool,
 
     // output file to write to
     pub output_file: Option<PathBuf>,
+
+    // block size used by reader threads
+    pub block_size: usize,
+
+    // size of the source device or file, set once known
+    pub source_size: u64,
+
+    // if set, output is split into segments of this maximum size
+    pub segment_size: Option<u64>,
+
+    // true if zero blocks are left as holes in dd mode
+    pub sparse: bool,
+
+    // case metadata stored in image
+    pub metadata: Metadata,
+
+    // number of reader threads which failed
+    pub errors: Arc<AtomicU64>,
+
+    // number of threads compressing and classifying blocks
+    pub nb_workers: usize,
 }
 
 impl From<&Args> for WriterParams {
     fn from(args: &Args) -> Self {
         Self {
             dd: args.dd,
-            compress: args.compress,
-            sha256: args.sha256,
-            blake3: args.blake3,
+            compress: args.compress.is_some(),
+            algorithm: args.compress.unwrap_or_default(),
+            level: args.level,
+            dictionary: None,
+            dedup: (args.dedup || args.cdc) && args.repo.is_none(),
+            dedup_verify: args.dedup_verify,
+            dedup_entries: args
+                .dedup_memory()
+                .map(|memory| (memory / DEDUP_ENTRY_LEN) as usize),
+            cdc: args.cdc,
+            repository: args.repo.clone(),
+            parent: None,
+            block_hash: args.block_hash(),
+            store_block_hashes: args.store_block_hashes(),
+            cbt: args.cbt.clone(),
+            changed_since: None,
+            changed_only: args.changed_only,
+            min_saving: args.min_saving,
+            hashes: args.hash_algorithms(),
+            hash_window: args.hash_window(),
+            hash_log: args.hash_log.clone(),
+            merkle: args.merkle,
             output_file: args.of.clone(),
+            block_size: args.block_size(),
+            source_size: 0,
+            segment_size: args.segment_size(),
+            sparse: args.sparse,
+            metadata: Metadata::default(),
+            errors: Arc::default(),
+            nb_workers: args.nb_workers(),
         }
     }
 }
 
+// what the writer thread gives back once all blocks are written
+#[derive(Debug, Default)]
+pub struct WriterSummary {
+    // digests of the source as (algorithm, digest)
+    pub digests: Vec<(String, String)>,
+
+    // Merkle root, if a tree was built
+    pub fingerprint: Option<String>,
+
+    // number of blocks per chunk type
+    pub chunks: BTreeMap<String, u64>,
+
+    // ranges of blocks changed since the sidecar given, as (first, last)
+    pub changes: Option<Vec<(u64, u64)>>,
+}
+
+// a block along with the chunk built from it by a transform worker, and its digest if needed
+type Block = (Vec<u8>, Chunk<'static>, Option<Vec<u8>>);
+
+// what a transform worker sends for a block: the serializer stops at the first error
+type Transformed = (u64, anyhow::Result<Block>);
+
+// blocks coming from reader threads, shared by all transform workers
+type SharedReceiver = Arc<Mutex<Receiver<(u64, Vec<u8>)>>>;
+
+// start transform workers and the serializer which writes their chunks in block order
 pub fn writer_thread(
     rx: Receiver<(u64, Vec<u8>)>,
     params: WriterParams,
-) -> JoinHandle<Option<String>> {
-    thread::spawn(move || {
-        // start initiating hashes
-        let mut hasher_sha = if params.sha256 {
-            Some(Sha256::new())
-        } else {
-            None
-        };
-        let mut hasher_blake3 = if params.blake3 {
-            Some(blake3::Hasher::new())
-        } else {
-            None
+) -> JoinHandle<anyhow::Result<WriterSummary>> {
+    let params = Arc::new(params);
+
+    // chunks replace blocks from there, numbered in order
+    let rx = if params.cdc {
+        let (tx, chunks_rx) = mpsc::channel();
+        let params = Arc::clone(&params);
+
+        debug!("starting chunker");
+        thread::spawn(move || chunker(rx, tx, &params));
+        chunks_rx
+    } else {
+        rx
+    };
+
+    // blocks are shared between workers, first come first served
+    let rx = Arc::new(Mutex::new(rx));
+    let (tx, chunks_rx) = mpsc::channel::<Transformed>();
+
+    for i in 0..params.nb_workers.max(1) {
+        let rx = Arc::clone(&rx);
+        let tx = tx.clone();
+        let params = Arc::clone(&params);
+
+        debug!("starting transform worker {i}");
+        thread::spawn(move || transform_worker(rx, tx, &params));
+    }
+
+    // serializer ends when all workers are done
+    drop(tx);
+    thread::spawn(move || serializer(chunks_rx, &params))
+}
+
+// zero detection and compression don't depend on other blocks so they're done in parallel
+fn transform_worker(rx: SharedReceiver, tx: Sender<Transformed>, params: &WriterParams) {
+    loop {
+        // lock is only held while waiting for a block
+        let received = rx.lock().unwrap().recv();
+        let Ok((block_index, buf)) = received else {
+            break;
         };
 
-        // this will help to serialize data coming from reader threads
-        let mut pending = BTreeMap::<u64, Vec<u8>>::new();
-        let mut next_block = 0;
+        let digest = params.block_hash.map(|h| h.digest(&buf));
+        let unchanged = match (&params.changed_since, &digest) {
+            (Some(previous), Some(digest)) => previous.get(block_index) == Some(digest),
+            _ => false,
+        };
 
-        // open output file for writing
-        let mut writer = if let Some(output_file) = &params.output_file {
-            let of = File::create(output_file).unwrap();
-            Some(BufWriter::new(of))
-        } else {
-            None
+        // the chunk is depending on writer params, unless the block didn't change since parent
+        let chunk = match &params.parent {
+            _ if params.changed_only && unchanged => Ok(Chunk::hole(buf.len())),
+            Some(parent)
+                if digest
+                    .as_ref()
+                    .is_some_and(|d| parent.unchanged(block_index, d)) =>
+            {
+                Ok(Chunk::parent_run(1))
+            }
+            _ => Chunk::try_from((buf.as_slice(), params)).map(Chunk::into_owned),
         };
+        trace!("block index={block_index} transformed");
 
-        let mut i = 0;
+        let transformed = chunk.map(|chunk| (buf, chunk, digest));
+        if tx.send((block_index, transformed)).is_err() {
+            break;
+        }
+    }
+}
 
-        while let Ok((block_index, buf)) = rx.recv() {
-            // println!("i:{i} block={block_index}");
-            i += 1;
+// put blocks back in order and cut the byte stream into content-defined chunks
+fn chunker(rx: Receiver<(u64, Vec<u8>)>, tx: Sender<(u64, Vec<u8>)>, params: &WriterParams) {
+    let mut chunker = Chunker::new(params.block_size);
+    let mut pending = BTreeMap::<u64, Vec<u8>>::new();
+    let mut next_block = 0;
+    let mut next_chunk = 0;
 
-            // Store received block
-            pending.insert(block_index, buf);
-            trace!("block index={block_index}");
+    let mut send = |chunks: Vec<Vec<u8>>| {
+        for chunk in chunks {
+            trace!("chunk index={next_chunk} size={}", chunk.len());
+            if tx.send((next_chunk, chunk)).is_err() {
+                return false;
+            }
+            next_chunk += 1;
+        }
+        true
+    };
 
-            // Hash any contiguous blocks in order
-            while let Some(buf) = pending.remove(&next_block) {
-                // calculate hash on this block if asked for
-                if let Some(ref mut h_sha256) = hasher_sha {
-                    h_sha256.update(&buf);
-                }
+    while let Ok((block_index, buf)) = rx.recv() {
+        pending.insert(block_index, buf);
 
-                if let Some(ref mut h_blake3) = hasher_blake3 {
-                    h_blake3.update(&buf);
-                }
+        while let Some(buf) = pending.remove(&next_block) {
+            if !send(chunker.push(&buf)) {
+                return;
+            }
+            next_block += 1;
+        }
+    }
+
+    // failed reads are sent as zeros, so only a reader thread which stopped early leaves a gap
+    if !pending.is_empty() {
+        warn!("{} blocks could not be chunked in order", pending.len());
+    }
+    send(chunker.finish());
+}
+
+// hash and write chunks in block order
+fn serializer(rx: Receiver<Transformed>, params: &WriterParams) -> anyhow::Result<WriterSummary> {
+    let start_time = SystemTime::now();
+    let mut bytes_read = 0u64;
+
+    // start initiating hashes, windows and Merkle tree being built on pool threads as well
+    let mut hashes = HashPool::new(&params.hashes);
+    if let Some(size) = params.hash_window {
+        hashes = hashes.with_windows(size, &params.hashes);
+    }
+    if params.merkle {
+        hashes = hashes.with_merkle(params.block_size as u64);
+    }
+
+    // this will help to serialize data coming from transform workers
+    let mut pending = BTreeMap::<u64, Block>::new();
+    let mut next_block = 0;
 
-                // the chunk is depending on writer params
-                // TODO
-                let chunk = Chunk::try_from((buf.as_slice(), &params)).unwrap();
-                debug!("chunk size: {} type: {:?}", chunk.len, chunk.chunk_type);
+    let mut chunks = BTreeMap::<String, u64>::new();
 
-                // write chunk
-                if let Some(ref mut w) = writer {
-                    chunk.write(w).ok();
+    // block digests are kept for the sidecar and the image, and compared with the previous ones
+    let mut block_hashes = params
+        .block_hash
+        .map(|algorithm| BlockHashes::new(algorithm, params.block_size as u64, params.source_size));
+    let mut changes = params.changed_since.as_ref().map(|_| Vec::new());
+
+    // open output file for writing
+    let mut writer = params
+        .output_file
+        .as_ref()
+        .map(|_| ImageWriter::create(params))
+        .transpose()?;
+
+    while let Ok((block_index, transformed)) = rx.recv() {
+        // Store received block
+        let block =
+            transformed.with_context(|| format!("unable to transform block {block_index}"))?;
+        pending.insert(block_index, block);
+        trace!("block index={block_index}");
+
+        // Hash any contiguous blocks in order
+        while let Some((buf, chunk, digest)) = pending.remove(&next_block) {
+            // calculate hash on this block if asked for
+            hashes.update(&buf);
+            bytes_read += buf.len() as u64;
+
+            if let (Some(block_hashes), Some(digest)) = (&mut block_hashes, &digest) {
+                block_hashes.push(digest);
+
+                if let (Some(previous), Some(changes)) = (&params.changed_since, &mut changes)
+                    && previous.get(next_block) != Some(digest)
+                {
+                    add_change(changes, next_block);
                 }
-                next_block += 1;
             }
+
+            debug!("chunk size: {} type: {:?}", chunk.len, chunk.chunk_type);
+
+            // write chunk
+            let chunk_type = match writer {
+                Some(ref mut w) => w.write_chunk(next_block, buf.len(), &chunk)?,
+                None => chunk.chunk_type,
+            };
+            *chunks.entry(format!("{chunk_type:?}")).or_default() += 1;
+            next_block += 1;
         }
+    }
+
+    // failed reads are sent as zeros, so only a reader thread which stopped early leaves a gap
+    if !pending.is_empty() {
+        warn!("{} blocks could not be written in order", pending.len());
+    }
+
+    let PoolOutput {
+        digests,
+        windows,
+        merkle,
+    } = hashes.finish();
+
+    let errors = params.errors.load(Ordering::Relaxed);
+    let trailer = Trailer {
+        completed: errors == 0 && bytes_read == params.source_size,
+        bytes_read,
+        start_time: epoch_secs(start_time),
+        end_time: epoch_secs(SystemTime::now()),
+        errors,
+        digests: digests
+            .into_iter()
+            .map(|(algorithm, digest)| (algorithm.to_string(), digest))
+            .collect(),
+    };
+    debug!("trailer: {:?}", trailer);
+
+    if let (Some(path), Some(windows)) = (&params.hash_log, &windows) {
+        windows.write_log(path)?;
+    }
+
+    if let Some(w) = writer {
+        let stored_hashes = block_hashes.as_ref().filter(|_| params.store_block_hashes);
+        w.finish(&trailer, windows.as_ref(), merkle.as_ref(), stored_hashes)?;
+    }
+
+    if let (Some(path), Some(block_hashes)) = (&params.cbt, &block_hashes) {
+        block_hashes.write(path)?;
+    }
 
-        if let Some(ref mut w) = writer {
-            w.flush().unwrap();
+    Ok(WriterSummary {
+        digests: trailer.digests,
+        fingerprint: merkle.map(|m| hex(&m.root)),
+        chunks,
+        changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+    use crate::{
+        hash::Hashes,
+        image_reader::ImageReader,
+        repository::Repository,
+        segment::SegmentReader,
+        test_util::{TempDir, random_bytes, write_image},
+    };
+
+    // parameters to write len bytes of 4096 bytes blocks to output
+    fn params_for(output: PathBuf, len: usize) -> WriterParams {
+        WriterParams {
+            output_file: Some(output),
+            block_size: 4096,
+            source_size: len as u64,
+            nb_workers: 4,
+            ..Default::default()
         }
+    }
+
+    // write blocks, sent in reverse order, to an image and decode it
+    fn roundtrip(
+        blocks: Vec<Vec<u8>>,
+        params: WriterParams,
+    ) -> anyhow::Result<(WriterSummary, Vec<u8>)> {
+        let path = params.output_file.clone().unwrap();
+        let summary = write_image(params, blocks.iter().map(Vec::as_slice))?;
+
+        let mut decoded = Vec::new();
+        ImageReader::open(SegmentReader::open(&path)?)?.read_to_end(&mut decoded)?;
+
+        Ok((summary, decoded))
+    }
+
+    #[test]
+    fn ordered() -> anyhow::Result<()> {
+        let dir = TempDir::new("writer");
+
+        // compressible, zero and incompressible blocks
+        let blocks: Vec<Vec<u8>> = (0..64u64)
+            .map(|i| match i % 3 {
+                0 => b"hello world".repeat(400)[..4096].to_vec(),
+                1 => vec![0u8; 4096],
+                _ => (0..4096)
+                    .map(|j| (j * 7 + i * 13) as u8 ^ (j >> 3) as u8)
+                    .collect(),
+            })
+            .collect();
+        let original = blocks.concat();
+
+        let params = WriterParams {
+            compress: true,
+            algorithm: Algorithm::Zstd,
+            hashes: vec![HashAlgorithm::Sha256],
+            ..params_for(dir.join("image.img"), original.len())
+        };
+        let (summary, decoded) = roundtrip(blocks, params)?;
+
+        let mut hashes = Hashes::new(&[HashAlgorithm::Sha256]);
+        hashes.update(&original);
+        assert_eq!(summary.digests[0].1, hashes.digests()[0].1);
+        assert_eq!(summary.chunks.values().sum::<u64>(), 64);
+        assert_eq!(decoded, original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn error() {
+        // the serializer gives up instead of panicking
+        let params = params_for("/nonexistent/image.img".into(), 4096);
+        assert!(write_image(params, [&[0u8; 4096][..]]).is_err());
+    }
+
+    #[test]
+    fn dedup() -> anyhow::Result<()> {
+        let dir = TempDir::new("dedup");
+
+        // 4 distinct blocks repeated 8 times
+        let blocks: Vec<Vec<u8>> = (0..32u64)
+            .map(|i| (0..4096).map(|j| (j * (i % 4 + 1)) as u8).collect())
+            .collect();
+        let original = blocks.concat();
+
+        // records are read back across segments to be compared
+        let params = WriterParams {
+            segment_size: Some(10000),
+            dedup: true,
+            dedup_verify: true,
+            ..params_for(dir.join("image.img"), original.len())
+        };
+        let (summary, decoded) = roundtrip(blocks.clone(), params)?;
+
+        assert_eq!(summary.chunks["Raw"], 4);
+        assert_eq!(summary.chunks["Reference"], 28);
+        assert_eq!(decoded, original);
+
+        // once the table is full, only blocks it holds are deduplicated
+        let params = WriterParams {
+            dedup: true,
+            dedup_entries: Some(2),
+            ..params_for(dir.join("small.img"), original.len())
+        };
+        let (summary, decoded) = roundtrip(blocks, params)?;
+
+        assert_eq!(summary.chunks["Raw"], 18);
+        assert_eq!(summary.chunks["Reference"], 14);
+        assert_eq!(decoded, original);
 
-        // Final SHA-256 hash if any
-        if let Some(h_sha256) = hasher_sha {
-            Some(format!("{:x}", h_sha256.finalize()))
-        } else if let Some(h_blake3) = hasher_blake3 {
-            Some(h_blake3.finalize().to_string())
-        } else {
-            None
+        Ok(())
+    }
+
+    #[test]
+    fn cdc() -> anyhow::Result<()> {
+        let dir = TempDir::new("cdc");
+
+        // the same data twice, shifted by a few bytes, and zeros in between
+        let data = random_bytes(1 << 18);
+        let original = [&data[..], &[0u8; 50000], b"shift", &data].concat();
+        let blocks: Vec<Vec<u8>> = original.chunks(4096).map(|b| b.to_vec()).collect();
+
+        let params = WriterParams {
+            cdc: true,
+            dedup: true,
+            compress: true,
+            ..params_for(dir.join("image.img"), original.len())
+        };
+        let (summary, decoded) = roundtrip(blocks, params)?;
+
+        // only chunks around the zeros and the shift differ
+        assert!(summary.chunks["Reference"] >= summary.chunks["Raw"] - 4);
+        assert_eq!(decoded, original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn repository() -> anyhow::Result<()> {
+        let dir = TempDir::new("repository");
+
+        let blocks: Vec<Vec<u8>> = (0..16u64)
+            .map(|i| match i % 4 {
+                0 => vec![0u8; 4096],
+                _ => (0..4096).map(|j| (j * i) as u8).collect(),
+            })
+            .collect();
+        let original = blocks.concat();
+
+        // the second acquisition of the same source adds nothing to the repository
+        for name in ["first.img", "second.img"] {
+            let params = WriterParams {
+                compress: true,
+                repository: Some(dir.join("repo")),
+                ..params_for(dir.join(name), original.len())
+            };
+            let (summary, decoded) = roundtrip(blocks.clone(), params)?;
+
+            assert_eq!(summary.chunks["Repository"], 12);
+            assert_eq!(decoded, original);
         }
-    })
+
+        let stats = Repository::open(&dir.join("repo"))?.stats()?;
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.logical_bytes, 2 * original.len() as u64);
+        assert_eq!(stats.chunks, 12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn incremental() -> anyhow::Result<()> {
+        let dir = TempDir::new("incremental");
+
+        let mut blocks: Vec<Vec<u8>> = (0..16u64)
+            .map(|i| (0..4096).map(|j| (j * (i + 1)) as u8).collect())
+            .collect();
+        let params = |name: &str, parent: Option<Arc<Parent>>| WriterParams {
+            hashes: vec![HashAlgorithm::Sha256],
+            block_hash: Some(BlockHash::Blake3),
+            store_block_hashes: true,
+            parent,
+            ..params_for(dir.join(name), 16 * 4096)
+        };
+        roundtrip(blocks.clone(), params("base.img", None))?;
+
+        // only changed blocks are stored
+        blocks[3] = vec![1u8; 4096];
+        blocks[10] = vec![0u8; 4096];
+        let parent = Parent::load(&dir.join("base.img"), 4096, None)?;
+        let (summary, decoded) =
+            roundtrip(blocks.clone(), params("incr.img", Some(Arc::new(parent))))?;
+
+        assert_eq!(summary.chunks["Parent"], 14);
+        assert_eq!(decoded, blocks.concat());
+
+        // the incremental image can be a parent too
+        let parent = Parent::load(&dir.join("incr.img"), 4096, None)?;
+        assert!(parent.unchanged(3, &BlockHash::Blake3.digest(&blocks[3])));
+
+        // without block hashes, the parent needs its sidecar
+        let without = WriterParams {
+            store_block_hashes: false,
+            cbt: Some(dir.join("plain.cbt")),
+            ..params("plain.img", None)
+        };
+        roundtrip(blocks.clone(), without)?;
+        assert!(Parent::load(&dir.join("plain.img"), 4096, None).is_err());
+        let parent = Parent::load(&dir.join("plain.img"), 4096, Some(&dir.join("plain.cbt")))?;
+        assert_eq!(parent.len(), 16);
+
+        // parent content must not change once referenced, even with its trailer untouched
+        let base = fs::read(dir.join("base.img"))?;
+        let mut tampered = base.clone();
+        tampered[1000] ^= 1;
+        fs::write(dir.join("base.img"), &tampered)?;
+        let mut decoder = ImageReader::open(SegmentReader::open(&dir.join("incr.img"))?)?;
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("its content doesn't give"));
+        fs::write(dir.join("base.img"), &base)?;
+
+        // a parent which moved is given instead of the recorded one
+        fs::rename(dir.join("base.img"), dir.join("moved.img"))?;
+        let mut decoder = ImageReader::open(SegmentReader::open(&dir.join("incr.img"))?)?;
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+        let mut decoder = ImageReader::open(SegmentReader::open(&dir.join("incr.img"))?)?;
+        decoder.set_parent(&dir.join("moved.img"))?;
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded)?;
+        assert_eq!(decoded, blocks.concat());
+
+        // even when another image took its path
+        roundtrip(vec![vec![2u8; 4096]], params("base.img", None))?;
+        let mut decoder = ImageReader::open(SegmentReader::open(&dir.join("incr.img"))?)?;
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"));
+        let mut decoder = ImageReader::open(SegmentReader::open(&dir.join("incr.img"))?)?;
+        decoder.set_parent(&dir.join("moved.img"))?;
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded)?;
+        assert_eq!(decoded, blocks.concat());
+
+        Ok(())
+    }
+
+    #[test]
+    fn changed_only() -> anyhow::Result<()> {
+        let dir = TempDir::new("changed-only");
+        let (copy, sidecar) = (dir.join("copy.dd"), dir.join("copy.cbt"));
+
+        let mut blocks: Vec<Vec<u8>> = (0..16u8).map(|i| vec![i; 4096]).collect();
+        let params = |changed_since: Option<Arc<BlockHashes>>| WriterParams {
+            dd: true,
+            block_hash: Some(BlockHash::Blake3),
+            cbt: Some(sidecar.clone()),
+            changed_only: changed_since.is_some(),
+            changed_since,
+            ..params_for(copy.clone(), 16 * 4096)
+        };
+        let write =
+            |blocks: &[Vec<u8>], params| write_image(params, blocks.iter().map(Vec::as_slice));
+        assert_eq!(write(&blocks, params(None))?.changes, None);
+
+        // only changed ranges are reported and written over the previous copy
+        blocks[3] = vec![0xff; 4096];
+        blocks[4] = vec![0xfe; 4096];
+        blocks[9] = vec![0xfd; 4096];
+        let previous = BlockHashes::read(&sidecar)?;
+        let summary = write(&blocks, params(Some(Arc::new(previous))))?;
+
+        assert_eq!(summary.changes, Some(vec![(3, 4), (9, 9)]));
+        assert_eq!(summary.chunks["Hole"], 13);
+        assert_eq!(fs::read(&copy)?, blocks.concat());
+        assert_eq!(BlockHashes::read(&sidecar)?.len(), 16);
+
+        Ok(())
+    }
 }